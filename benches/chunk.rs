@@ -0,0 +1,60 @@
+use amethyst::core::math::Vector3;
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+use kyro::{marching_cubes, matrix_3d::Matrix3D, terrain::Terrain, terrain_config::TerrainConfig};
+
+fn terrain() -> Terrain {
+    Terrain::from_config(TerrainConfig::new(1234u64)).unwrap()
+}
+
+/// A chunk the default config puts the surface through.
+fn surface_chunk() -> Vector3<i32> {
+    Vector3::new(0, 0, 0)
+}
+
+/// A chunk well below the default config's floor, entirely solid.
+fn underground_chunk(terrain: &Terrain) -> Vector3<i32> {
+    Vector3::new(0, (-300.0 / terrain.chunk_size().y).floor() as i32, 0)
+}
+
+/// A chunk well above the default config's hills, entirely air.
+fn sky_chunk(terrain: &Terrain) -> Vector3<i32> {
+    Vector3::new(0, (300.0 / terrain.chunk_size().y).ceil() as i32, 0)
+}
+
+fn generate(c: &mut Criterion) {
+    let terrain = terrain();
+    let mut matrix = Matrix3D::new(0, 0, 0);
+    c.bench_function("fill chunk densities", |b| {
+        b.iter(|| terrain.fill_matrix_with_margin(black_box(surface_chunk()), 0, &mut matrix))
+    });
+}
+
+fn mesh(c: &mut Criterion) {
+    let terrain = terrain();
+    let mut matrix = Matrix3D::new(0, 0, 0);
+    terrain.fill_matrix_with_margin(surface_chunk(), 0, &mut matrix);
+    c.bench_function("mesh chunk densities", |b| {
+        b.iter(|| {
+            marching_cubes::get_mesh_data(black_box(&matrix), terrain.scale(), terrain.cutoff())
+        })
+    });
+}
+
+fn generate_and_mesh(c: &mut Criterion) {
+    let terrain = terrain();
+    c.bench_function("get_chunk", |b| b.iter(|| terrain.get_chunk(black_box(surface_chunk()))));
+}
+
+fn skip_empty(c: &mut Criterion) {
+    let terrain = terrain();
+    let underground = underground_chunk(&terrain);
+    c.bench_function("get_chunk underground", |b| {
+        b.iter(|| terrain.get_chunk(black_box(underground)))
+    });
+    let sky = sky_chunk(&terrain);
+    c.bench_function("get_chunk sky", |b| b.iter(|| terrain.get_chunk(black_box(sky))));
+}
+
+criterion_group!(benches, generate, mesh, generate_and_mesh, skip_empty);
+criterion_main!(benches);
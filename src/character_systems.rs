@@ -1,22 +1,235 @@
 use amethyst::{
     core::{
-        math::{Matrix4, UnitQuaternion, Vector3},
-        Transform,
+        math::{Matrix4, UnitQuaternion, Vector2, Vector3},
+        Parent, Time, Transform,
     },
     ecs::prelude::*,
-    input::{InputEvent, StringBindings},
+    input::{InputEvent, InputHandler, ScrollDirection, StringBindings},
     renderer::Camera,
     shrev::EventChannel,
 };
-use amethyst_physics::prelude::*;
+use amethyst_physics::{prelude::*, servers::ContactEvent};
+use noise::{NoiseFn, Perlin};
+use std::collections::HashMap;
 
-use crate::components::*;
+use crate::{
+    chunk_systems::TerrainEditEvent,
+    components::*,
+    controls,
+    terrain::{SharedTerrain, Terrain},
+};
 
-const MOUSE_SENSITIVITY: f32 = 0.2;
+/// Radians turned per pixel of mouse motion.
+const MOUSE_SENSITIVITY: f32 = 0.0033;
+const GAMEPAD_SENSITIVITY: f32 = 4.0;
+const STICK_DEADZONE: f32 = 0.15;
 const MAX_PITCH_ANGLE: f32 = 80.0;
-const FORCE_MULTIPLIER: f32 = 200.0;
-const JUMP_IMPULSE: f32 = 30.0;
-const MAX_THRUST_VEL: f32 = 5.0;
+const ZOOM_STEP: f32 = 1.0;
+/// Below this gap the eased boom length snaps onto its target.
+const ZOOM_SNAP_DISTANCE: f32 = 0.01;
+/// Gap kept between the camera and the terrain it collides with.
+const CAMERA_SKIN_WIDTH: f32 = 0.3;
+const MIN_CAMERA_DISTANCE: f32 = 0.2;
+/// How quickly the camera moves back out once the terrain in the way clears, per second.
+const CAMERA_RESTORE_RATE: f32 = 6.0;
+/// Extra free space needed before the camera starts moving back out.
+const CAMERA_RESTORE_HYSTERESIS: f32 = 0.3;
+/// How far below the feet the ground normal is looked for.
+const GROUND_PROBE_DISTANCE: f32 = 0.5;
+/// Depth of the body's centre below the water surface at which swimming starts, and the
+/// lower depth at which it stops, so bobbing at the surface doesn't flicker between modes.
+const SWIM_ENTER_DEPTH: f32 = 0.2;
+const SWIM_EXIT_DEPTH: f32 = -0.2;
+/// Largest camera rotation, in degrees, and offset of a shake at full trauma.
+const SHAKE_MAX_ANGLE: f32 = 4.0;
+const SHAKE_MAX_OFFSET: f32 = 0.15;
+/// How fast the shake wanders, in noise cells per second.
+const SHAKE_FREQUENCY: f64 = 12.0;
+const REACH: f32 = 6.0;
+const BRUSH_RADIUS: f32 = 2.0;
+const BRUSH_STRENGTH: f32 = 0.5;
+const EDIT_COOLDOWN: f32 = 0.15;
+/// How far a placed brush is moved out of the surface along its normal, so placing doesn't
+/// fill the space right in front of the camera.
+const PLACE_OFFSET: f32 = 0.5;
+
+/// Player preferences for the camera controls.
+#[derive(Debug, Clone)]
+pub struct CameraSettings {
+    /// Moving the mouse or stick up looks down instead of up.
+    pub invert_y: bool,
+    /// Radians turned per pixel the mouse moves. Mouse motion is already per frame, so it
+    /// isn't scaled by the frame time.
+    pub mouse_sensitivity: f32,
+    /// Turn rate, in radians per second, of a fully tilted "LookX"/"LookY" stick.
+    pub gamepad_sensitivity: f32,
+    /// Stick tilt, out of 1, below which the stick is treated as centred.
+    pub stick_deadzone: f32,
+}
+
+impl Default for CameraSettings {
+    fn default() -> Self {
+        CameraSettings {
+            invert_y: false,
+            mouse_sensitivity: MOUSE_SENSITIVITY,
+            gamepad_sensitivity: GAMEPAD_SENSITIVITY,
+            stick_deadzone: STICK_DEADZONE,
+        }
+    }
+}
+
+/// Player preferences for the head bob of `HeadBobSystem`.
+#[derive(Debug, Clone)]
+pub struct HeadBobSettings {
+    pub enabled: bool,
+    /// Height of the bob at full walking speed.
+    pub vertical_amplitude: f32,
+    /// Sideways sway at full walking speed.
+    pub lateral_amplitude: f32,
+    /// Strides per metre walked, each stride bobs up and down twice and sways once.
+    pub frequency: f32,
+    /// How quickly the bob fades in and out as the character starts and stops, per second.
+    pub smoothing: f32,
+}
+
+impl Default for HeadBobSettings {
+    fn default() -> Self {
+        HeadBobSettings {
+            enabled: true,
+            vertical_amplitude: 0.05,
+            lateral_amplitude: 0.025,
+            frequency: 0.35,
+            smoothing: 8.0,
+        }
+    }
+}
+
+/// Removes the uphill part of a horizontal `force` on ground whose `normal` is steeper
+/// than `max_slope` degrees, so the character can't push its way up. Flatter ground and
+/// forces along or down the slope are left alone.
+pub fn limit_slope(force: Vector3<f32>, normal: Vector3<f32>, max_slope: f32) -> Vector3<f32> {
+    if normal.y >= max_slope.to_radians().cos() {
+        return force;
+    }
+    let downhill = match Vector3::new(normal.x, 0.0, normal.z).try_normalize(std::f32::EPSILON) {
+        Some(downhill) => downhill,
+        None => return force,
+    };
+    let uphill = -force.dot(&downhill);
+    if uphill <= 0.0 {
+        return force;
+    }
+    force + downhill * uphill
+}
+
+/// Applies a radial deadzone to a stick, rescaling the rest of the range so the output
+/// still starts at zero and never exceeds a length of one.
+pub fn apply_deadzone(stick: Vector2<f32>, deadzone: f32) -> Vector2<f32> {
+    let magnitude = stick.norm();
+    if magnitude <= deadzone || magnitude == 0.0 {
+        return Vector2::zeros();
+    }
+    let scaled = ((magnitude - deadzone) / (1.0 - deadzone)).min(1.0);
+    stick * (scaled / magnitude)
+}
+
+/// Whether a body that isn't `jumping`, has been off the ground for `air_time` seconds
+/// and last jumped `since_jump` seconds ago may jump. Walking off a ledge still allows a
+/// jump within the coyote time.
+pub fn can_jump(config: &MovementConfig, jumping: bool, air_time: f32, since_jump: f32) -> bool {
+    !jumping && air_time <= config.coyote_time && since_jump >= config.jump_cooldown
+}
+
+/// Whether to jump as soon as allowed, for a Jump press `since_press` seconds ago that
+/// hasn't jumped yet, with Jump still `held` or not. A press shortly before landing is
+/// buffered until then.
+pub fn wants_jump(config: &MovementConfig, since_press: f32, held: bool) -> bool {
+    // Landing while holding Jump only jumps again when bunny hopping
+    since_press <= config.jump_buffer_time || (config.bunny_hop && held)
+}
+
+/// Adds the move `stick` to the held movement `keys`, scaled by how far it is tilted. The
+/// sum is kept within a length of one so diagonals aren't faster, while a half tilted
+/// stick still walks.
+pub fn combine_move_input(keys: Vector3<f32>, stick: Vector2<f32>) -> Vector3<f32> {
+    let mut move_input = keys;
+    move_input.x += stick.x;
+    move_input.z -= stick.y;
+    let magnitude = move_input.norm();
+    if magnitude > 1.0 {
+        move_input /= magnitude;
+    }
+    move_input
+}
+
+/// Direction to fly in: the movement input turned the way the `camera` looks, plus
+/// straight up by `up` for Jump and straight down while `down` is held for Crouch.
+pub fn fly_direction(
+    camera: &Matrix4<f32>,
+    move_input: Vector3<f32>,
+    up: f32,
+    down: bool,
+) -> Vector3<f32> {
+    let mut direction = camera.transform_vector(&move_input);
+    direction.y += up;
+    if down {
+        direction.y -= 1.0;
+    }
+    direction
+}
+
+/// How quickly, in speed per second, the horizontal velocity follows the input. Without
+/// input the ground slows the body down by friction, except on slopes it slides down.
+/// In the air it steers less and keeps its momentum.
+pub fn steering_rate(config: &MovementConfig, moving: bool, airborne: bool, sliding: bool) -> f32 {
+    match (moving, airborne) {
+        (true, false) => config.acceleration,
+        (true, true) => config.acceleration * config.air_control,
+        (false, false) if sliding => 0.0,
+        (false, false) => config.friction,
+        (false, true) => config.friction * config.air_braking,
+    }
+}
+
+/// Force turning the horizontal `velocity` towards `target` by at most `rate * dt` this
+/// step. Forces are velocity changes per second here, the body has unit mass.
+pub fn steering_force(
+    velocity: Vector3<f32>,
+    target: Vector3<f32>,
+    rate: f32,
+    dt: f32,
+) -> Vector3<f32> {
+    if dt <= 0.0 {
+        return Vector3::zeros();
+    }
+    let mut change = target - Vector3::new(velocity.x, 0.0, velocity.z);
+    let max_change = rate * dt;
+    if change.norm() > max_change {
+        change *= max_change / change.norm();
+    }
+    change / dt
+}
+
+/// Pitch and yaw, in radians, to turn a camera by for the mouse motion summed over a
+/// frame, in pixels, and the raw look stick. Mouse motion is per frame already, so only
+/// the stick is scaled by `dt`.
+pub fn look_delta(
+    mouse: Vector2<f32>,
+    stick: Vector2<f32>,
+    settings: &CameraSettings,
+    dt: f32,
+) -> Vector2<f32> {
+    // The stick adds to the mouse rather than replacing it; up and right turn like
+    // moving the mouse up and right
+    let stick = apply_deadzone(stick, settings.stick_deadzone);
+    let stick_turn = settings.gamepad_sensitivity * dt;
+    let mut pitch = mouse.y * settings.mouse_sensitivity - stick.y * stick_turn;
+    let yaw = -mouse.x * settings.mouse_sensitivity - stick.x * stick_turn;
+    if settings.invert_y {
+        pitch = -pitch;
+    }
+    Vector2::new(pitch, yaw)
+}
 
 #[derive(Debug)]
 pub struct CameraMotionSystem {
@@ -34,74 +247,81 @@ impl CameraMotionSystem {
 impl<'s> System<'s> for CameraMotionSystem {
     #[allow(clippy::type_complexity)]
     type SystemData = (
-        ReadExpect<'s, PhysicsTime>,
+        Read<'s, Time>,
         ReadExpect<'s, EventChannel<InputEvent<StringBindings>>>,
-        ReadStorage<'s, CameraBoomHandle>,
+        Read<'s, InputHandler<StringBindings>>,
+        Read<'s, CameraSettings>,
+        ReadStorage<'s, Camera>,
+        ReadStorage<'s, Parent>,
+        WriteStorage<'s, CameraBoomHandle>,
+        WriteStorage<'s, CameraOrientation>,
         WriteStorage<'s, Transform>,
     );
 
     fn run(
         &mut self,
-        (physics_time, input_event_channel, camera_boom_handles, mut transforms): Self::SystemData,
+        (
+            time,
+            input_event_channel,
+            input,
+            settings,
+            cameras,
+            parents,
+            mut camera_boom_handles,
+            mut camera_orientations,
+            mut transforms,
+        ): Self::SystemData,
     ) {
         // Capture the input
-        let motion = {
-            let mut m_motion_x = 0.0;
-            let mut m_motion_y = 0.0;
-
-            for e in input_event_channel.read(self.input_event_reader.as_mut().unwrap()) {
-                if let InputEvent::MouseMoved { delta_x, delta_y } = e {
-                    m_motion_x = *delta_y;
-                    m_motion_y = *delta_x * -1.0;
-                    break;
+        let mut zoom = 0.0;
+        let mut mouse = Vector2::zeros();
+        // Sum every motion of the frame, so fast mice and frame hitches don't drop input
+        for e in input_event_channel.read(self.input_event_reader.as_mut().unwrap()) {
+            match e {
+                InputEvent::MouseMoved { delta_x, delta_y } => {
+                    mouse += Vector2::new(*delta_x, *delta_y);
                 }
+                InputEvent::MouseWheelMoved(ScrollDirection::ScrollUp) => zoom -= ZOOM_STEP,
+                InputEvent::MouseWheelMoved(ScrollDirection::ScrollDown) => zoom += ZOOM_STEP,
+                _ => {}
             }
-            (
-                m_motion_x * MOUSE_SENSITIVITY,
-                m_motion_y * MOUSE_SENSITIVITY,
-            )
-        };
-
-        for (transform, _) in (&mut transforms, &camera_boom_handles).join() {
-            // Clamp the pitch rotation by avoiding further rotations.
-            let pitch_clamper = {
-                let angles = transform.isometry().rotation.euler_angles();
-
-                let mut pitch_deg = angles.0.to_degrees();
-
-                if angles.2.abs() > std::f32::consts::FRAC_PI_2 {
-                    // Invert the pitch
-                    if pitch_deg < 0.0 {
-                        pitch_deg = pitch_deg + 180.0;
-                    } else {
-                        pitch_deg = pitch_deg - 180.0;
-                    }
-                }
-                if pitch_deg > MAX_PITCH_ANGLE || pitch_deg < -MAX_PITCH_ANGLE {
-                    if pitch_deg.signum() != motion.0.signum() {
-                        1.0
-                    } else {
-                        0.0
-                    }
-                } else {
-                    1.0
-                }
-            };
+        }
+        let stick = Vector2::new(
+            input.axis_value(controls::LOOK_X).unwrap_or(0.0),
+            input.axis_value(controls::LOOK_Y).unwrap_or(0.0),
+        );
+        let motion = look_delta(mouse, stick, &settings, time.delta_seconds());
 
-            let delta_rotation_pitch = UnitQuaternion::from_axis_angle(
-                &Vector3::x_axis(),
-                motion.0 * pitch_clamper * physics_time.delta_seconds(),
-            );
-            let delta_rotation_yaw = UnitQuaternion::from_axis_angle(
-                &Vector3::y_axis(),
-                motion.1 * physics_time.delta_seconds(),
-            );
+        let max_pitch = MAX_PITCH_ANGLE.to_radians();
+        for (transform, boom, orientation) in (
+            &mut transforms,
+            &mut camera_boom_handles,
+            &mut camera_orientations,
+        )
+            .join()
+        {
+            // Zoom moves the target, the boom eases towards it over a few frames
+            boom.target_length = (boom.target_length + zoom)
+                .max(boom.min_length)
+                .min(boom.max_length);
+            let ease = 1.0 - (-boom.zoom_smoothing * time.delta_seconds()).exp();
+            boom.length += (boom.target_length - boom.length) * ease;
+            if (boom.target_length - boom.length).abs() < ZOOM_SNAP_DISTANCE {
+                boom.length = boom.target_length;
+            }
 
-            transform.isometry_mut().rotation =
-                delta_rotation_yaw * transform.isometry().rotation * delta_rotation_pitch;
+            orientation.turn(motion, max_pitch);
+            transform.isometry_mut().rotation = orientation.rotation();
 
             break; // Actually is supported only 1 player
         }
+
+        // Pull the camera back along the boom, the camera looks down -Z so back is +Z
+        for (transform, parent, _) in (&mut transforms, &parents, &cameras).join() {
+            if let Some(boom) = camera_boom_handles.get(parent.entity) {
+                transform.set_translation_z(boom.length);
+            }
+        }
     }
 
     fn setup(&mut self, world: &mut World) {
@@ -111,12 +331,331 @@ impl<'s> System<'s> for CameraMotionSystem {
     }
 }
 
+/// Shortens the camera boom when terrain is between the boom pivot and the camera, so the
+/// camera never ends up inside the ground. Runs after `CameraMotionSystem`.
+///
+/// The camera snaps in as soon as something is in the way and eases back out once it
+/// clears. It only starts moving out when the free space is `CAMERA_RESTORE_HYSTERESIS`
+/// longer than the current distance, so terrain that barely grazes the boom doesn't make
+/// the camera pump in and out.
+///
+/// amethyst_physics 0.2 has no ray or shape queries, so this casts through the terrain
+/// density with `Terrain::raycast` instead of through the physics world. Terrain chunks
+/// are the only colliders besides the character, and the character's own collider can't
+/// be hit this way. The cast sees the unsmoothed density, so with `Terrain::set_smoothing`
+/// the camera stops at the raw surface rather than the meshed one.
+#[derive(Debug, Default)]
+pub struct CameraCollisionSystem {
+    /// Current camera distance of every camera, eased towards the free length.
+    distances: HashMap<Entity, f32>,
+}
+
+impl<'s> System<'s> for CameraCollisionSystem {
+    #[allow(clippy::type_complexity)]
+    type SystemData = (
+        Entities<'s>,
+        ReadExpect<'s, PhysicsTime>,
+        ReadExpect<'s, SharedTerrain>,
+        ReadStorage<'s, Camera>,
+        ReadStorage<'s, Parent>,
+        ReadStorage<'s, CameraBoomHandle>,
+        WriteStorage<'s, Transform>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            entities,
+            physics_time,
+            terrain,
+            cameras,
+            parents,
+            camera_boom_handles,
+            mut transforms,
+        ): Self::SystemData,
+    ) {
+        let terrain = terrain.read().unwrap();
+        let ease = 1.0 - (-CAMERA_RESTORE_RATE * physics_time.delta_seconds()).exp();
+
+        let mut lengths = vec![];
+        for (entity, parent, _) in (&entities, &parents, &cameras).join() {
+            let (boom, boom_transform) = match (
+                camera_boom_handles.get(parent.entity),
+                transforms.get(parent.entity),
+            ) {
+                (Some(boom), Some(transform)) => (boom, transform),
+                _ => continue,
+            };
+            if boom.length <= 0.0 {
+                self.distances.remove(&entity);
+                continue;
+            }
+            let matrix = boom_transform.global_matrix();
+            let pivot = Vector3::new(matrix[(0, 3)], matrix[(1, 3)], matrix[(2, 3)]);
+            let backward = matrix.transform_vector(&Vector3::z());
+
+            let free = match terrain.raycast(pivot, backward, boom.length) {
+                Some(hit) => (hit.distance - CAMERA_SKIN_WIDTH).max(MIN_CAMERA_DISTANCE),
+                None => boom.length,
+            };
+            let free = free.min(boom.length);
+            let distance = self.distances.entry(entity).or_insert(boom.length);
+            if free <= *distance {
+                *distance = free;
+            } else if free >= boom.length || free > *distance + CAMERA_RESTORE_HYSTERESIS {
+                *distance += (free - *distance) * ease;
+            }
+            lengths.push((entity, *distance));
+        }
+
+        for (entity, length) in lengths {
+            if let Some(transform) = transforms.get_mut(entity) {
+                transform.set_translation_z(length);
+            }
+        }
+    }
+}
+
+/// Bobs the camera up and down and sways it sideways as the character walks. The bob
+/// follows the distance walked, so it speeds up with the character, and its size follows
+/// the horizontal speed up to `MovementConfig::max_speed`, fading out when the character
+/// stops, leaves the ground or stops walking. Only moves the camera within its boom, so
+/// it runs after `CameraCollisionSystem` without touching the look rotation.
+#[derive(Debug, Default)]
+pub struct HeadBobSystem {
+    /// Position in the stride, in radians.
+    phase: f32,
+    /// Current size of the bob, out of the full amplitude.
+    weight: f32,
+}
+
+impl<'s> System<'s> for HeadBobSystem {
+    #[allow(clippy::type_complexity)]
+    type SystemData = (
+        Read<'s, Time>,
+        ReadExpect<'s, PhysicsWorld<f32>>,
+        Read<'s, HeadBobSettings>,
+        ReadStorage<'s, CharacterBody>,
+        ReadStorage<'s, GroundContact>,
+        ReadStorage<'s, MovementConfig>,
+        ReadStorage<'s, PhysicsHandle<PhysicsRigidBodyTag>>,
+        ReadStorage<'s, Camera>,
+        ReadStorage<'s, Parent>,
+        ReadStorage<'s, CameraBoomHandle>,
+        WriteStorage<'s, Transform>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            time,
+            physics_world,
+            settings,
+            character_bodies,
+            ground_contacts,
+            movement_configs,
+            rigid_body_tags,
+            cameras,
+            parents,
+            camera_boom_handles,
+            mut transforms,
+        ): Self::SystemData,
+    ) {
+        let dt = time.delta_seconds();
+        let mut speed = 0.0;
+        let mut target = 0.0;
+        for (body, ground_contact, movement_config, body_tag) in (
+            &character_bodies,
+            &ground_contacts,
+            &movement_configs,
+            &rigid_body_tags,
+        )
+            .join()
+        {
+            let velocity = physics_world
+                .rigid_body_server()
+                .linear_velocity(body_tag.get());
+            speed = Vector2::new(velocity.x, velocity.z).norm();
+            if settings.enabled
+                && ground_contact.grounded
+                && body.mode == MovementMode::Walking
+                && movement_config.max_speed > 0.0
+            {
+                target = (speed / movement_config.max_speed).min(1.0);
+            }
+            break; // Actually is supported only 1 player
+        }
+
+        self.weight += (target - self.weight) * (1.0 - (-settings.smoothing * dt).exp());
+        self.phase += speed * settings.frequency * std::f32::consts::PI * 2.0 * dt;
+        self.phase %= std::f32::consts::PI * 2.0;
+        if self.weight < 0.001 {
+            // Start the next walk from the beginning of a stride
+            self.weight = 0.0;
+            self.phase = 0.0;
+        }
+
+        let offset_x = self.phase.sin() * settings.lateral_amplitude * self.weight;
+        let offset_y = -(self.phase * 2.0).cos() * settings.vertical_amplitude * self.weight;
+        for (transform, parent, _) in (&mut transforms, &parents, &cameras).join() {
+            if camera_boom_handles.get(parent.entity).is_some() {
+                transform.set_translation_x(offset_x);
+                transform.set_translation_y(offset_y);
+            }
+        }
+    }
+}
+
+/// Sent to shake the camera, e.g. for an explosion or a heavy landing. `intensity` is the
+/// trauma it adds, where 1 is the strongest shake, and it fades out linearly over
+/// `duration` seconds.
+#[derive(Debug, Clone, Copy)]
+pub struct CameraShake {
+    pub intensity: f32,
+    pub duration: f32,
+}
+
+/// Shakes the camera by the `CameraShake` events sent to it. The trauma of overlapping
+/// shakes adds up, capped at 1, and the camera is rotated and moved by smooth noise scaled
+/// by the trauma squared, so small shakes stay subtle and the shake eases out as it
+/// decays. Replaces the camera's own rotation and adds to its position within the boom,
+/// so it runs after the camera, collision and head bob systems.
+#[derive(Debug)]
+pub struct CameraShakeSystem {
+    shake_reader: Option<ReaderId<CameraShake>>,
+    /// Running shakes with the seconds since they started.
+    shakes: Vec<(CameraShake, f32)>,
+    noise: Perlin,
+    time: f64,
+}
+
+impl CameraShakeSystem {
+    pub fn new() -> Self {
+        CameraShakeSystem {
+            shake_reader: None,
+            shakes: vec![],
+            noise: Perlin::new(),
+            time: 0.0,
+        }
+    }
+
+    /// Sum of the running shakes' remaining intensity, capped at 1.
+    pub fn trauma(&self) -> f32 {
+        let trauma: f32 = self
+            .shakes
+            .iter()
+            .map(|(shake, elapsed)| shake.intensity * (1.0 - elapsed / shake.duration))
+            .sum();
+        return trauma.max(0.0).min(1.0);
+    }
+
+    /// Starts a shake, ignoring ones without intensity or duration.
+    fn add_shake(&mut self, shake: CameraShake) {
+        if shake.intensity > 0.0 && shake.duration > 0.0 {
+            self.shakes.push((shake, 0.0));
+        }
+    }
+
+    /// Moves the running shakes on by `dt` seconds, dropping those that ended.
+    fn advance(&mut self, dt: f32) {
+        for (_, elapsed) in self.shakes.iter_mut() {
+            *elapsed += dt;
+        }
+        self.shakes.retain(|(shake, elapsed)| *elapsed < shake.duration);
+        self.time += f64::from(dt);
+    }
+
+    /// Noise in [-1, 1] for one axis of the shake, each axis reads its own row.
+    fn sample(&self, axis: usize) -> f32 {
+        return self.noise.get([self.time * SHAKE_FREQUENCY, axis as f64 * 7.5 + 0.5]) as f32;
+    }
+}
+
+impl<'s> System<'s> for CameraShakeSystem {
+    type SystemData = (
+        Read<'s, Time>,
+        Read<'s, EventChannel<CameraShake>>,
+        ReadStorage<'s, Camera>,
+        ReadStorage<'s, Parent>,
+        ReadStorage<'s, CameraBoomHandle>,
+        WriteStorage<'s, Transform>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            time,
+            shake_events,
+            cameras,
+            parents,
+            camera_boom_handles,
+            mut transforms,
+        ): Self::SystemData,
+    ) {
+        self.advance(time.delta_seconds());
+        for shake in shake_events.read(self.shake_reader.as_mut().unwrap()) {
+            self.add_shake(*shake);
+        }
+
+        let trauma = self.trauma();
+        let shake = trauma * trauma;
+        let angle = SHAKE_MAX_ANGLE.to_radians() * shake;
+        let rotation = UnitQuaternion::from_euler_angles(
+            self.sample(0) * angle,
+            self.sample(1) * angle,
+            self.sample(2) * angle,
+        );
+        let offset = Vector3::new(self.sample(3), self.sample(4), 0.0) * SHAKE_MAX_OFFSET * shake;
+
+        for (transform, parent, _) in (&mut transforms, &parents, &cameras).join() {
+            if camera_boom_handles.get(parent.entity).is_some() {
+                transform.isometry_mut().rotation = rotation;
+                transform.prepend_translation(offset);
+            }
+        }
+    }
+
+    fn setup(&mut self, world: &mut World) {
+        Self::SystemData::setup(world);
+        let mut channel = world.fetch_mut::<EventChannel<CameraShake>>();
+        self.shake_reader = Some(channel.register_reader());
+    }
+}
+
+/// Whether a crouched body centred at `center` has room to stand up. Rays go up from the
+/// centre and from around the edge of the capsule to where the standing head would be.
+fn has_headroom(terrain: &Terrain, center: Vector3<f32>, config: &MovementConfig) -> bool {
+    let shift = config.stand_half_height - config.crouch_half_height;
+    let reach = shift * 2.0 + config.crouch_half_height + config.capsule_radius;
+    let edge = config.capsule_radius * std::f32::consts::FRAC_1_SQRT_2;
+    let offsets = [(0.0, 0.0), (edge, 0.0), (-edge, 0.0), (0.0, edge), (0.0, -edge)];
+    return offsets.iter().all(|&(x, z)| {
+        terrain
+            .raycast(center + Vector3::new(x, 0.0, z), Vector3::y(), reach)
+            .is_none()
+    });
+}
+
 pub struct CharacterMotionControllerSystem {
     input_event_reader: Option<ReaderId<InputEvent<StringBindings>>>,
     horizontal_input: Vector3<f32>,
     vertical_input: f32,
+    /// Seconds since the last jump started.
     jump_time: f32,
-    sprint: bool
+    /// Seconds since Jump was pressed without jumping yet, infinite once used.
+    jump_buffer: f32,
+    jump_released: bool,
+    jumping: bool,
+    sprint: bool,
+    crouch: bool,
+    toggle_fly: bool,
+    flying: bool,
+    contacts: Vec<ContactEvent<f32>>,
+    /// How far the body moved up or down by crouching or standing since the camera last
+    /// followed it.
+    crouch_shift: f32,
+    /// Tilt, out of 1, below which the "MoveX"/"MoveY" stick is treated as centred.
+    pub stick_deadzone: f32,
 }
 
 impl CharacterMotionControllerSystem {
@@ -125,21 +664,108 @@ impl CharacterMotionControllerSystem {
             input_event_reader: None,
             horizontal_input: Vector3::zeros(),
             vertical_input: 0.0,
-            jump_time: 0.0,
-            sprint: false
+            jump_time: std::f32::INFINITY,
+            jump_buffer: std::f32::INFINITY,
+            jump_released: false,
+            jumping: false,
+            sprint: false,
+            crouch: false,
+            toggle_fly: false,
+            flying: false,
+            contacts: Vec::new(),
+            crouch_shift: 0.0,
+            stick_deadzone: STICK_DEADZONE,
+        }
+    }
+
+    /// Starts the jump timers and uses up the Jump press and the coyote time, so walking
+    /// off a ledge only allows a single jump.
+    fn start_jump(&mut self, ground: &mut GroundContact) {
+        self.jumping = true;
+        self.jump_time = 0.0;
+        self.jump_buffer = std::f32::INFINITY;
+        ground.air_time = std::f32::INFINITY;
+    }
+
+    /// Updates the held movement input from a pressed or released action.
+    fn handle_input(&mut self, event: &InputEvent<StringBindings>) {
+        if let InputEvent::ActionPressed(action) = event {
+            match action.as_str() {
+                controls::FORWARD => {
+                    self.horizontal_input.z -= 1.0;
+                }
+                controls::BACKWARD => {
+                    self.horizontal_input.z += 1.0;
+                }
+                controls::RIGHT => {
+                    self.horizontal_input.x -= 1.0;
+                }
+                controls::LEFT => {
+                    self.horizontal_input.x += 1.0;
+                }
+                controls::JUMP => {
+                    self.vertical_input += 1.0;
+                    self.jump_buffer = 0.0;
+                }
+                controls::SPRINT => {
+                    self.sprint = true;
+                }
+                controls::CROUCH => {
+                    self.crouch = true;
+                }
+                controls::TOGGLE_FLY => {
+                    self.toggle_fly = true;
+                }
+                _ => {}
+            }
+        } else if let InputEvent::ActionReleased(action) = event {
+            match action.as_str() {
+                controls::FORWARD => {
+                    self.horizontal_input.z += 1.0;
+                }
+                controls::BACKWARD => {
+                    self.horizontal_input.z -= 1.0;
+                }
+                controls::RIGHT => {
+                    self.horizontal_input.x += 1.0;
+                }
+                controls::LEFT => {
+                    self.horizontal_input.x -= 1.0;
+                }
+                controls::JUMP => {
+                    self.vertical_input -= 1.0;
+                    self.jump_released = true;
+                }
+                controls::SPRINT => {
+                    self.sprint = false;
+                }
+                controls::CROUCH => {
+                    self.crouch = false;
+                }
+                _ => {}
+            }
         }
     }
 }
 
 impl<'s> System<'s> for CharacterMotionControllerSystem {
+    #[allow(clippy::type_complexity)]
     type SystemData = (
         ReadExpect<'s, PhysicsWorld<f32>>,
         ReadExpect<'s, PhysicsTime>,
         ReadExpect<'s, EventChannel<InputEvent<StringBindings>>>,
-        ReadStorage<'s, CharacterBody>,
+        Read<'s, InputHandler<StringBindings>>,
+        ReadExpect<'s, SharedTerrain>,
+        WriteStorage<'s, CharacterBody>,
+        ReadStorage<'s, MovementConfig>,
         ReadStorage<'s, Camera>,
         ReadStorage<'s, PhysicsHandle<PhysicsRigidBodyTag>>,
-        ReadStorage<'s, Transform>,
+        ReadStorage<'s, PhysicsHandle<PhysicsShapeTag>>,
+        ReadStorage<'s, Parent>,
+        ReadStorage<'s, CameraBoomHandle>,
+        WriteStorage<'s, Transform>,
+        WriteStorage<'s, GroundContact>,
+        WriteStorage<'s, Stamina>,
     );
 
     fn run(
@@ -148,98 +774,399 @@ impl<'s> System<'s> for CharacterMotionControllerSystem {
             physics_world,
             physics_time,
             input_event_channel,
-            character_bodies,
+            input,
+            terrain,
+            mut character_bodies,
+            movement_configs,
             cameras,
             rigid_body_tags,
-            transforms,
+            shape_tags,
+            parents,
+            camera_boom_handles,
+            mut transforms,
+            mut ground_contacts,
+            mut staminas,
         ): Self::SystemData,
     ) {
         for e in input_event_channel.read(self.input_event_reader.as_mut().unwrap()) {
-            if let InputEvent::ActionPressed(action) = e {
-                match action.as_str() {
-                    "Forward" => {
-                        self.horizontal_input.z -= 1.0;
-                    }
-                    "Backward" => {
-                        self.horizontal_input.z += 1.0;
-                    }
-                    "Right" => {
-                        self.horizontal_input.x -= 1.0;
-                    }
-                    "Left" => {
-                        self.horizontal_input.x += 1.0;
-                    }
-                    "Jump" => {
-                        self.vertical_input += 1.0;
-                    }
-                    "Sprint" => {
-                        self.sprint = true;
-                    }
-                    _ => {}
-                }
-            } else if let InputEvent::ActionReleased(action) = e {
-                match action.as_str() {
-                    "Forward" => {
-                        self.horizontal_input.z += 1.0;
-                    }
-                    "Backward" => {
-                        self.horizontal_input.z -= 1.0;
-                    }
-                    "Right" => {
-                        self.horizontal_input.x += 1.0;
-                    }
-                    "Left" => {
-                        self.horizontal_input.x -= 1.0;
-                    }
-                    "Jump" => {
-                        self.vertical_input -= 1.0;
-                    }
-                    "Sprint" => {
-                        self.sprint = false;
-                    }
-                    _ => {}
-                }
-            }
-        }
-        let horizontal_input;
-        if self.sprint {
-            horizontal_input = self.horizontal_input.scale(3.0);
-        } else {
-            horizontal_input = self.horizontal_input;
+            self.handle_input(e);
         }
 
+        let stick = apply_deadzone(
+            Vector2::new(
+                input.axis_value(controls::MOVE_X).unwrap_or(0.0),
+                input.axis_value(controls::MOVE_Y).unwrap_or(0.0),
+            ),
+            self.stick_deadzone,
+        );
+        let move_input = combine_move_input(self.horizontal_input, stick);
+
         let mut camera_pos = Matrix4::<f32>::identity();
         for (t, _) in (&transforms, &cameras).join() {
             camera_pos = t.global_matrix().clone();
         }
 
-        for (body_tag, _) in (&rigid_body_tags, &character_bodies).join() {
+        let terrain = terrain.read().unwrap();
+        for (body_tag, shape_tag, transform, ground, config, body, stamina) in (
+            &rigid_body_tags,
+            &shape_tags,
+            &mut transforms,
+            &mut ground_contacts,
+            &movement_configs,
+            &mut character_bodies,
+            (&mut staminas).maybe(),
+        )
+            .join()
+        {
+            if self.toggle_fly {
+                self.toggle_fly = false;
+                self.flying = !self.flying;
+                // A kinematic body ignores gravity and forces, so it can be moved directly
+                let mode = if self.flying {
+                    BodyMode::Kinematic
+                } else {
+                    BodyMode::Dynamic
+                };
+                let server = physics_world.rigid_body_server();
+                server.set_mode(body_tag.get(), mode);
+                server.set_linear_velocity(body_tag.get(), &Vector3::zeros());
+            }
+
+            // Swim once the body is deep enough in water, and only stop once it's clearly out
+            let depth = match terrain.sea_level() {
+                Some(sea_level) if terrain.is_underwater(*transform.translation()) => {
+                    sea_level - transform.translation().y
+                }
+                Some(sea_level) => (sea_level - transform.translation().y).min(SWIM_EXIT_DEPTH),
+                None => SWIM_EXIT_DEPTH,
+            };
+            body.mode = if self.flying {
+                MovementMode::Flying
+            } else if depth > SWIM_ENTER_DEPTH {
+                MovementMode::Swimming
+            } else if depth < SWIM_EXIT_DEPTH || body.mode == MovementMode::Flying {
+                MovementMode::Walking
+            } else {
+                body.mode
+            };
+            let swimming = body.mode == MovementMode::Swimming;
+
+            // Crouching shrinks the capsule from the top and lowers the body by as much, so
+            // the feet stay where they are. Standing up waits until there's room overhead
+            let wants_crouch = self.crouch && !swimming && !self.flying;
+            if wants_crouch != body.crouched
+                && (wants_crouch || has_headroom(&terrain, *transform.translation(), config))
+            {
+                body.crouched = wants_crouch;
+                let shift = config.stand_half_height - config.crouch_half_height;
+                let shift = if body.crouched { -shift } else { shift };
+                physics_world
+                    .shape_server()
+                    .update_description(shape_tag.get(), &config.capsule(body.crouched));
+                transform.prepend_translation(Vector3::new(0.0, shift, 0.0));
+                physics_world
+                    .rigid_body_server()
+                    .set_transform(body_tag.get(), transform.isometry());
+                self.crouch_shift += shift;
+            }
+            let half_height = config.half_height(body.crouched);
+
+            // Sprinting drains stamina, bodies without a stamina pool sprint forever
+            let wants_sprint = self.sprint
+                && !body.crouched
+                && !swimming
+                && !self.flying
+                && move_input != Vector3::zeros();
+            let sprint = match stamina {
+                Some(stamina) => stamina.update(wants_sprint, physics_time.delta_seconds()),
+                None => wants_sprint,
+            };
+
+            // Sprinting, crouching and swimming change the top speed, not the input
+            let speed_multiplier = if swimming {
+                // Crouch dives while swimming rather than slowing down
+                config.swim_multiplier
+            } else if body.crouched {
+                // Crouching wins over sprinting
+                config.crouch_multiplier
+            } else if sprint {
+                config.sprint_multiplier
+            } else {
+                1.0
+            };
+            let horizontal_input = move_input;
+
+            if self.flying {
+                // Fly where the camera looks, with Jump and Crouch moving straight up and down
+                let direction =
+                    fly_direction(&camera_pos, horizontal_input, self.vertical_input, self.crouch);
+                let speed = config.fly_speed * speed_multiplier;
+                transform.prepend_translation(direction * speed * physics_time.delta_seconds());
+                // A kinematic body only moves when it's given its new pose
+                physics_world
+                    .rigid_body_server()
+                    .set_transform(body_tag.get(), transform.isometry());
+                self.jump_buffer = std::f32::INFINITY;
+                self.jump_released = false;
+                break;
+            }
+
             let velocity = physics_world
-            .rigid_body_server()
-            .linear_velocity(body_tag.get());
-            
-            physics_world.rigid_body_server().apply_force(
-                body_tag.get(),
-                &Vector3::new(0.0, self.vertical_input * JUMP_IMPULSE * 0.0f32.max(MAX_THRUST_VEL - velocity[1]), 0.0),
-            );
-            self.jump_time = 0.0;
-
-            // Apply motion force
-            let mut force = camera_pos.transform_vector(&horizontal_input);
-            force.y = 0.0; // Don't apply any force on Y axis
+                .rigid_body_server()
+                .linear_velocity(body_tag.get());
+
+            // Check for contacts below the body to know whether it stands on something
+            self.contacts.clear();
             physics_world
                 .rigid_body_server()
-                .apply_force(body_tag.get(), &(force * FORCE_MULTIPLIER));
+                .contact_events(body_tag.get(), &mut self.contacts);
+            // Contacts on the bottom half sphere of the capsule count as ground
+            let feet = transform.translation().y - half_height;
+            ground.grounded = self.contacts.iter().any(|c| c.location.y <= feet);
+            if ground.grounded {
+                ground.air_time = 0.0;
+            } else {
+                ground.air_time += physics_time.delta_seconds();
+            }
+            ground.normal = terrain
+                .raycast(
+                    *transform.translation(),
+                    -Vector3::y(),
+                    half_height + config.capsule_radius + GROUND_PROBE_DISTANCE,
+                )
+                .map_or_else(Vector3::y, |hit| hit.normal);
 
-            // Compute breaking force
-            let mut bk_force = (velocity / physics_time.delta_seconds()) * -1.0;
-            bk_force.y = 0.0;
+            // Jump once on press, keep pushing up while the button is held for a moment, and
+            // cut the jump short if it's released early. A press shortly before landing jumps
+            // on landing, and a press shortly after walking off a ledge still jumps
+            self.jump_time += physics_time.delta_seconds();
+            self.jump_buffer += physics_time.delta_seconds();
+            if self.jumping && (velocity.y <= 0.0 || swimming) {
+                self.jumping = false;
+            }
+            let can_jump = can_jump(config, self.jumping, ground.air_time, self.jump_time);
+            let wants_jump = wants_jump(config, self.jump_buffer, self.vertical_input > 0.0);
+            if swimming {
+                self.jump_buffer = std::f32::INFINITY;
+                // Float up in proportion to how much of the body is under water, swim up and
+                // down with Jump and Crouch, and let the water slow vertical motion
+                let body_half_height = half_height + config.capsule_radius;
+                let submerged = (depth + body_half_height)
+                    .max(0.0)
+                    .min(body_half_height * 2.0);
+                let dive = if self.crouch { 1.0 } else { 0.0 };
+                let lift = config.buoyancy * submerged
+                    + (self.vertical_input - dive) * config.swim_force
+                    - velocity.y * config.water_drag;
+                physics_world
+                    .rigid_body_server()
+                    .apply_force(body_tag.get(), &Vector3::new(0.0, lift, 0.0));
+            } else if wants_jump && can_jump {
+                // A buffered tap that was already released only gets the short jump
+                let jump_velocity = if self.vertical_input > 0.0 {
+                    config.max_jump_velocity
+                } else {
+                    config.min_jump_velocity
+                };
+                physics_world.rigid_body_server().set_linear_velocity(
+                    body_tag.get(),
+                    &Vector3::new(velocity.x, jump_velocity, velocity.z),
+                );
+                self.start_jump(ground);
+            } else if self.jump_released && self.jumping {
+                if velocity.y > config.min_jump_velocity {
+                    physics_world.rigid_body_server().set_linear_velocity(
+                        body_tag.get(),
+                        &Vector3::new(velocity.x, config.min_jump_velocity, velocity.z),
+                    );
+                }
+                self.jumping = false;
+            } else if self.jumping
+                && self.vertical_input > 0.0
+                && self.jump_time < config.jump_hold_time
+            {
+                physics_world.rigid_body_server().apply_force(
+                    body_tag.get(),
+                    &Vector3::new(0.0, config.jump_hold_force, 0.0),
+                );
+            }
+            self.jump_released = false;
+
+            // Accelerate towards the velocity of the input, at most the max speed
+            let mut direction = camera_pos.transform_vector(&horizontal_input);
+            direction.y = 0.0; // Don't move on the Y axis
+            let steep = ground.normal.y < config.max_slope.to_radians().cos();
+            let sliding = ground.grounded && steep && !swimming;
+            if sliding {
+                // Can't walk up ground that's too steep, and slowly slide down it
+                direction = limit_slope(direction, ground.normal, config.max_slope);
+                let downhill = Vector3::new(ground.normal.x, 0.0, ground.normal.z);
+                if let Some(downhill) = downhill.try_normalize(std::f32::EPSILON) {
+                    physics_world
+                        .rigid_body_server()
+                        .apply_force(body_tag.get(), &(downhill * config.slide_force));
+                }
+            }
+            let target = direction * config.max_speed * speed_multiplier;
+
+            // Only the horizontal velocity is steered, gravity and jumps keep the vertical
+            // one. Water has its own drag
+            let airborne = !ground.grounded && !swimming;
+            let moving = horizontal_input != Vector3::zeros();
+            let rate = steering_rate(config, moving, airborne, sliding);
+            let force = steering_force(velocity, target, rate, physics_time.delta_seconds());
             physics_world
                 .rigid_body_server()
-                .apply_force(body_tag.get(), &bk_force);
+                .apply_force(body_tag.get(), &force);
 
             break; // Actually only 1 player is allowed;
         }
+
+        // The camera keeps its height when the body jumps down or up by crouching, then
+        // eases to the eyes, which drop twice as far as the body's centre
+        for (parent, _, transform) in (&parents, &camera_boom_handles, &mut transforms).join() {
+            let (body, config) = match (
+                character_bodies.get(parent.entity),
+                movement_configs.get(parent.entity),
+            ) {
+                (Some(body), Some(config)) => (body, config),
+                _ => continue,
+            };
+            let target = config.half_height(body.crouched) - config.stand_half_height;
+            let ease = 1.0
+                - (-config.crouch_camera_smoothing * physics_time.delta_seconds()).exp();
+            let mut y = transform.translation().y - self.crouch_shift;
+            y += (target - y) * ease;
+            transform.set_translation_y(y);
+            self.crouch_shift = 0.0;
+        }
+    }
+
+    fn setup(&mut self, world: &mut World) {
+        Self::SystemData::setup(world);
+        let mut ie = world.fetch_mut::<EventChannel<InputEvent<StringBindings>>>();
+        self.input_event_reader = Some(ie.register_reader());
+    }
+}
+
+/// Digs and places terrain where the camera is looking while the "Dig" or "Place" action
+/// is held, repeating every `cooldown` seconds, and sends a `TerrainEditEvent` for the
+/// chunks that need remeshing.
+///
+/// The aim is cast with `Terrain::raycast` rather than a physics query, which
+/// amethyst_physics 0.2 doesn't offer. Only terrain can be edited anyway, and the cast
+/// can't stop on the character's own collider.
+pub struct TerrainInteractionSystem {
+    input_event_reader: Option<ReaderId<InputEvent<StringBindings>>>,
+    dig: bool,
+    place: bool,
+    /// Seconds until the next edit is allowed.
+    cooldown_left: f32,
+    /// Furthest distance from the character that can be edited.
+    pub reach: f32,
+    pub brush_radius: f32,
+    /// Density change at the centre of the brush.
+    pub brush_strength: f32,
+    /// Seconds between edits while an action is held.
+    pub cooldown: f32,
+}
+
+impl TerrainInteractionSystem {
+    pub fn new() -> Self {
+        TerrainInteractionSystem {
+            input_event_reader: None,
+            dig: false,
+            place: false,
+            cooldown_left: 0.0,
+            reach: REACH,
+            brush_radius: BRUSH_RADIUS,
+            brush_strength: BRUSH_STRENGTH,
+            cooldown: EDIT_COOLDOWN,
+        }
+    }
+}
+
+impl<'s> System<'s> for TerrainInteractionSystem {
+    #[allow(clippy::type_complexity)]
+    type SystemData = (
+        Read<'s, Time>,
+        ReadExpect<'s, SharedTerrain>,
+        ReadExpect<'s, EventChannel<InputEvent<StringBindings>>>,
+        Write<'s, EventChannel<TerrainEditEvent>>,
+        ReadStorage<'s, Camera>,
+        ReadStorage<'s, CharacterBody>,
+        ReadStorage<'s, Transform>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            time,
+            terrain,
+            input_event_channel,
+            mut edit_events,
+            cameras,
+            character_bodies,
+            transforms,
+        ): Self::SystemData,
+    ) {
+        for e in input_event_channel.read(self.input_event_reader.as_mut().unwrap()) {
+            match e {
+                InputEvent::ActionPressed(action) if action == controls::DIG => {
+                    self.dig = true;
+                }
+                InputEvent::ActionPressed(action) if action == controls::PLACE => {
+                    self.place = true;
+                }
+                InputEvent::ActionReleased(action) if action == controls::DIG => {
+                    self.dig = false;
+                }
+                InputEvent::ActionReleased(action) if action == controls::PLACE => {
+                    self.place = false;
+                }
+                _ => {}
+            }
+        }
+
+        self.cooldown_left = (self.cooldown_left - time.delta_seconds()).max(0.0);
+        if !(self.dig || self.place) || self.cooldown_left > 0.0 {
+            return;
+        }
+
+        let camera = match (&transforms, &cameras).join().next() {
+            Some((transform, _)) => transform.global_matrix(),
+            None => return,
+        };
+        let character = match (&transforms, &character_bodies).join().next() {
+            Some((transform, _)) => transform.global_matrix(),
+            None => return,
+        };
+        let origin = Vector3::new(camera[(0, 3)], camera[(1, 3)], camera[(2, 3)]);
+        let forward = camera.transform_vector(&-Vector3::z());
+        let character_pos = Vector3::new(character[(0, 3)], character[(1, 3)], character[(2, 3)]);
+
+        // Aim from the camera so edits land under the crosshair, but measure the reach
+        // from the character so zooming the camera out doesn't extend it
+        let max_dist = self.reach + (origin - character_pos).norm();
+        let mut terrain = terrain.write().unwrap();
+        let hit = match terrain.raycast(origin, forward, max_dist) {
+            Some(hit) if (hit.position - character_pos).norm() <= self.reach => hit,
+            _ => return,
+        };
+
+        // Digging wins when both are held
+        let affected = if self.dig {
+            terrain.modify_density(hit.position, self.brush_radius, -self.brush_strength)
+        } else {
+            let center = hit.position + hit.normal * PLACE_OFFSET;
+            terrain.modify_density(center, self.brush_radius, self.brush_strength)
+        };
+        self.cooldown_left = self.cooldown;
+        if !affected.is_empty() {
+            edit_events.single_write(TerrainEditEvent {
+                affected_chunks: affected.into_iter().collect(),
+            });
+        }
     }
 
     fn setup(&mut self, world: &mut World) {
@@ -248,3 +1175,318 @@ impl<'s> System<'s> for CharacterMotionControllerSystem {
         self.input_event_reader = Some(ie.register_reader());
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DT: f32 = 1.0 / 60.0;
+
+    /// Force of a body with `velocity` holding full input along `direction` on flat ground.
+    fn walk_force(
+        config: &MovementConfig,
+        velocity: Vector3<f32>,
+        direction: Vector3<f32>,
+    ) -> Vector3<f32> {
+        let rate = steering_rate(config, true, false, false);
+        steering_force(velocity, direction * config.max_speed, rate, DT)
+    }
+
+    #[test]
+    fn movement_configs_change_the_force() {
+        let default = MovementConfig::default();
+        let snappy = MovementConfig {
+            acceleration: default.acceleration * 2.0,
+            ..MovementConfig::default()
+        };
+        let forward = -Vector3::z();
+        let slow = walk_force(&default, Vector3::zeros(), forward);
+        let fast = walk_force(&snappy, Vector3::zeros(), forward);
+        assert!((slow.norm() - default.acceleration).abs() < 1e-3);
+        assert!((fast.norm() - snappy.acceleration).abs() < 1e-3);
+    }
+
+    #[test]
+    fn invert_y_flips_the_pitch() {
+        let mouse = Vector2::new(3.0, -12.0);
+        let normal = CameraSettings::default();
+        let inverted = CameraSettings {
+            invert_y: true,
+            ..CameraSettings::default()
+        };
+        let up = look_delta(mouse, Vector2::zeros(), &normal, DT);
+        let down = look_delta(mouse, Vector2::zeros(), &inverted, DT);
+        assert!(up.x != 0.0);
+        assert_eq!(down.x, -up.x);
+        assert_eq!(down.y, up.y);
+        // Clamping still stops the pitch at the limit in the inverted direction
+        let max_pitch = MAX_PITCH_ANGLE.to_radians();
+        let mut orientation = CameraOrientation::default();
+        for _ in 0..1000 {
+            orientation.turn(down, max_pitch);
+        }
+        assert_eq!(orientation.pitch, max_pitch * down.x.signum());
+    }
+
+    /// Horizontal speed a body starting at rest reaches holding `input` for two seconds.
+    fn top_speed(config: &MovementConfig, input: Vector3<f32>) -> f32 {
+        let mut velocity = Vector3::zeros();
+        for _ in 0..120 {
+            velocity += walk_force(config, velocity, input) * DT;
+        }
+        velocity.norm()
+    }
+
+    #[test]
+    fn diagonals_are_as_fast_as_cardinals() {
+        let config = MovementConfig::default();
+        let forward = combine_move_input(Vector3::new(0.0, 0.0, -1.0), Vector2::zeros());
+        let diagonal = combine_move_input(Vector3::new(1.0, 0.0, -1.0), Vector2::zeros());
+        assert!((diagonal.norm() - 1.0).abs() < 1e-6);
+        let start_forward = walk_force(&config, Vector3::zeros(), forward);
+        let start_diagonal = walk_force(&config, Vector3::zeros(), diagonal);
+        assert!((start_forward.norm() - start_diagonal.norm()).abs() < 1e-3);
+        assert!((top_speed(&config, forward) - top_speed(&config, diagonal)).abs() < 1e-3);
+        assert_eq!(combine_move_input(Vector3::zeros(), Vector2::zeros()), Vector3::zeros());
+        // A half tilted stick isn't scaled up
+        let half = combine_move_input(Vector3::zeros(), Vector2::new(0.0, 0.5));
+        assert_eq!(half, Vector3::new(0.0, 0.0, -0.5));
+    }
+
+    #[test]
+    fn remapped_jump_still_jumps() {
+        use amethyst::input::{Button, VirtualKeyCode};
+
+        let source = include_str!("../assets/input_bindings.ron").replace("Key(Space)", "Key(J)");
+        let bindings = controls::parse_bindings(&source).unwrap();
+        let key = [Button::Key(VirtualKeyCode::J)];
+        let action = bindings
+            .actions()
+            .find(|&action| bindings.action_bindings(action).any(|combo| combo == &key[..]))
+            .expect("J is bound")
+            .clone();
+        assert_eq!(action, controls::JUMP);
+
+        let mut system = CharacterMotionControllerSystem::new();
+        system.handle_input(&InputEvent::ActionPressed(action.clone()));
+        assert_eq!(system.vertical_input, 1.0);
+        assert_eq!(system.jump_buffer, 0.0);
+        system.handle_input(&InputEvent::ActionReleased(action));
+        assert_eq!(system.vertical_input, 0.0);
+        assert!(system.jump_released);
+    }
+
+    #[test]
+    fn steep_slopes_zero_the_uphill_force() {
+        // 60 degree slope rising towards -x, so its normal leans towards +x
+        let normal = Vector3::new(60f32.to_radians().sin(), 60f32.to_radians().cos(), 0.0);
+        let uphill = Vector3::new(-3.0, 0.0, 2.0);
+        let limited = limit_slope(uphill, normal, 45.0);
+        assert!(limited.x.abs() < 1e-6);
+        assert_eq!(limited.z, 2.0);
+        // Downhill and on walkable ground nothing changes
+        let downhill = Vector3::new(3.0, 0.0, 2.0);
+        assert_eq!(limit_slope(downhill, normal, 45.0), downhill);
+        assert_eq!(limit_slope(uphill, normal, 70.0), uphill);
+    }
+
+    #[test]
+    fn airborne_steering_is_weaker_than_grounded() {
+        let config = MovementConfig::default();
+        let velocity = Vector3::new(1.0, -2.0, 0.0);
+        let target = -Vector3::z() * config.max_speed;
+        let force = |airborne| {
+            let rate = steering_rate(&config, true, airborne, false);
+            steering_force(velocity, target, rate, DT)
+        };
+        let (grounded, airborne) = (force(false), force(true));
+        assert!(airborne.norm() < grounded.norm());
+        assert!((airborne.norm() - grounded.norm() * config.air_control).abs() < 1e-3);
+        // Letting go in the air keeps most of the momentum
+        let braking = |airborne| steering_rate(&config, false, airborne, false);
+        assert!(braking(true) < braking(false));
+        // The vertical velocity is never steered
+        assert_eq!(grounded.y, 0.0);
+        assert_eq!(airborne.y, 0.0);
+    }
+
+    #[test]
+    fn constant_input_never_exceeds_the_max_speed() {
+        let config = MovementConfig::default();
+        let direction = Vector3::new(1.0, 0.0, -1.0).normalize();
+        for &multiplier in &[1.0, config.sprint_multiplier] {
+            let max_speed = config.max_speed * multiplier;
+            let rate = steering_rate(&config, true, false, false);
+            let mut velocity = Vector3::zeros();
+            for _ in 0..1000 {
+                velocity += steering_force(velocity, direction * max_speed, rate, DT) * DT;
+                assert!(velocity.norm() <= max_speed + 1e-4);
+            }
+            assert!((velocity.norm() - max_speed).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn coyote_time_ends_at_its_boundary() {
+        let config = MovementConfig::default();
+        let long_ago = std::f32::INFINITY;
+        assert!(can_jump(&config, false, 0.0, long_ago));
+        assert!(can_jump(&config, false, config.coyote_time, long_ago));
+        assert!(!can_jump(&config, false, config.coyote_time + 1e-3, long_ago));
+        assert!(!can_jump(&config, true, 0.0, long_ago));
+        // The cooldown ends at its boundary too
+        assert!(can_jump(&config, false, 0.0, config.jump_cooldown));
+        assert!(!can_jump(&config, false, 0.0, config.jump_cooldown - 1e-3));
+    }
+
+    #[test]
+    fn jump_buffer_ends_at_its_boundary() {
+        let config = MovementConfig {
+            bunny_hop: false,
+            ..MovementConfig::default()
+        };
+        assert!(wants_jump(&config, 0.0, false));
+        assert!(wants_jump(&config, config.jump_buffer_time, false));
+        assert!(!wants_jump(&config, config.jump_buffer_time + 1e-3, false));
+        // A used press is infinitely old, holding Jump alone doesn't jump again
+        assert!(!wants_jump(&config, std::f32::INFINITY, true));
+        let bunny_hop = MovementConfig {
+            bunny_hop: true,
+            ..config
+        };
+        assert!(wants_jump(&bunny_hop, std::f32::INFINITY, true));
+    }
+
+    #[test]
+    fn alternating_look_keeps_the_horizon_level() {
+        let settings = CameraSettings::default();
+        let max_pitch = MAX_PITCH_ANGLE.to_radians();
+        let mut orientation = CameraOrientation::default();
+        for i in 0..500 {
+            let sign = if i % 2 == 0 { 1.0 } else { -1.0 };
+            let mouse = Vector2::new(700.0 * sign, 900.0 * sign * (i % 7) as f32);
+            orientation.turn(look_delta(mouse, Vector2::zeros(), &settings, DT), max_pitch);
+            assert!(orientation.pitch.abs() <= max_pitch);
+            // No roll: the camera's right stays horizontal
+            let right = orientation.rotation() * Vector3::x();
+            assert!(right.y.abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn shakes_decay_to_nothing_after_their_duration() {
+        let mut system = CameraShakeSystem::new();
+        system.add_shake(CameraShake {
+            intensity: 0.8,
+            duration: 0.5,
+        });
+        system.add_shake(CameraShake {
+            intensity: 0.6,
+            duration: 1.0,
+        });
+        // Overlapping shakes add up, capped at full trauma
+        assert_eq!(system.trauma(), 1.0);
+        let mut last = system.trauma();
+        // A frame past the longest duration
+        for _ in 0..61 {
+            system.advance(DT);
+            assert!(system.trauma() <= last);
+            last = system.trauma();
+        }
+        assert_eq!(system.trauma(), 0.0);
+        assert!(system.shakes.is_empty());
+    }
+
+    #[test]
+    fn mouse_look_ignores_the_frame_time() {
+        let settings = CameraSettings::default();
+        let mouse = Vector2::new(-25.0, 40.0);
+        let slow = look_delta(mouse, Vector2::zeros(), &settings, 1.0 / 30.0);
+        let fast = look_delta(mouse, Vector2::zeros(), &settings, 1.0 / 240.0);
+        assert_eq!(slow, fast);
+        assert_eq!(slow, Vector2::new(40.0, 25.0) * settings.mouse_sensitivity);
+        // The stick is a rate, so it still scales with the frame time
+        let stick = Vector2::new(0.0, 1.0);
+        let slow = look_delta(Vector2::zeros(), stick, &settings, 1.0 / 30.0);
+        let fast = look_delta(Vector2::zeros(), stick, &settings, 1.0 / 240.0);
+        assert!((slow.x - fast.x * 8.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn forward_and_left_is_as_fast_as_forward() {
+        let held_input = |actions: &[&str]| {
+            let mut system = CharacterMotionControllerSystem::new();
+            for &action in actions {
+                system.handle_input(&InputEvent::ActionPressed(action.to_string()));
+            }
+            combine_move_input(system.horizontal_input, Vector2::zeros())
+        };
+        let forward = held_input(&[controls::FORWARD]);
+        let diagonal = held_input(&[controls::FORWARD, controls::LEFT]);
+        assert!((forward.norm() - diagonal.norm()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn tuned_configs_stay_within_the_max_speed_on_flat_ground() {
+        // Snappy enough to reach the max speed in a single long frame
+        let config = MovementConfig {
+            acceleration: 500.0,
+            friction: 500.0,
+            max_speed: 2.5,
+            ..MovementConfig::default()
+        };
+        let dt = 0.1;
+        let mut velocity = Vector3::new(0.0, -3.0, 0.0);
+        for i in 0..200 {
+            // Turn around every few frames, then let go
+            let moving = i < 150;
+            let direction = if (i / 10) % 2 == 0 { Vector3::x() } else { -Vector3::z() };
+            let target = if moving { direction * config.max_speed } else { Vector3::zeros() };
+            let rate = steering_rate(&config, moving, false, false);
+            let force = steering_force(velocity, target, rate, dt);
+            assert_eq!(force.y, 0.0);
+            velocity += force * dt;
+            let horizontal = Vector3::new(velocity.x, 0.0, velocity.z);
+            assert!(horizontal.norm() <= config.max_speed + 1e-4);
+        }
+        assert!(Vector3::new(velocity.x, 0.0, velocity.z).norm() < 1e-4);
+        assert_eq!(velocity.y, -3.0);
+    }
+
+    #[test]
+    fn a_ledge_allows_a_single_coyote_jump() {
+        // A coyote time longer than the cooldown must not allow a second jump in the air
+        let config = MovementConfig {
+            coyote_time: 0.5,
+            jump_cooldown: 0.1,
+            ..MovementConfig::default()
+        };
+        let mut system = CharacterMotionControllerSystem::new();
+        let mut ground = GroundContact {
+            grounded: false,
+            air_time: 0.05,
+            ..GroundContact::default()
+        };
+        assert!(can_jump(&config, system.jumping, ground.air_time, system.jump_time));
+        system.start_jump(&mut ground);
+        // The jump ends at its peak, still inside the coyote time but past the cooldown
+        system.jumping = false;
+        system.jump_time = config.jump_cooldown;
+        ground.air_time += config.jump_cooldown;
+        assert!(!can_jump(&config, system.jumping, ground.air_time, system.jump_time));
+        assert_eq!(system.jump_buffer, std::f32::INFINITY);
+    }
+
+    #[test]
+    fn jump_and_crouch_fly_straight_up_and_down() {
+        let camera = Matrix4::identity();
+        let forward = Vector3::new(0.0, 0.0, -1.0);
+        let down = Vector3::new(0.0, -1.0, 0.0);
+        assert_eq!(fly_direction(&camera, Vector3::zeros(), 0.0, true), down);
+        assert_eq!(fly_direction(&camera, Vector3::zeros(), 1.0, true), Vector3::zeros());
+        assert_eq!(fly_direction(&camera, forward, 1.0, false), Vector3::new(0.0, 1.0, -1.0));
+        // Looking straight down, flying forward dives
+        let looking_down = Matrix4::from_euler_angles(-std::f32::consts::FRAC_PI_2, 0.0, 0.0);
+        assert!((fly_direction(&looking_down, forward, 0.0, false) - down).norm() < 1e-5);
+    }
+}
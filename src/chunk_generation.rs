@@ -0,0 +1,282 @@
+use amethyst::core::math::Vector3;
+use std::{
+    cmp::Ordering,
+    collections::{BinaryHeap, HashMap},
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+};
+
+use crate::{marching_cubes::MeshData, matrix_3d::Matrix3D, terrain::SharedTerrain};
+
+/// A request to mesh the chunk at the given chunk coordinate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ChunkGenRequest(pub Vector3<i32>);
+
+/// Generation order of a chunk: chunks in the column under the focus come first, since
+/// falling through missing ground is worse than a gap on the horizon, then the rest by
+/// squared distance.
+fn priority(chunk: Vector3<i32>, focus: Vector3<i32>) -> (bool, i64) {
+    let d = chunk.map(i64::from) - focus.map(i64::from);
+    let below = d.x == 0 && d.z == 0 && d.y <= 0;
+    (!below, d.dot(&d))
+}
+
+#[derive(PartialEq, Eq)]
+struct QueuedChunk {
+    priority: (bool, i64),
+    request: ChunkGenRequest,
+    generation: u64,
+}
+
+impl Ord for QueuedChunk {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // Reversed so the max-heap pops the lowest priority value first
+        other.priority.cmp(&self.priority)
+    }
+}
+
+impl PartialOrd for QueuedChunk {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[derive(Default)]
+struct Queue {
+    requests: BinaryHeap<QueuedChunk>,
+    /// Generation of each chunk requested and not yet finished or cancelled. Cancelled
+    /// requests stay in `requests` and are dropped when they're popped.
+    pending: HashMap<Vector3<i32>, u64>,
+    next_generation: u64,
+    focus: Vector3<i32>,
+    closed: bool,
+}
+
+impl Queue {
+    fn push(&mut self, request: ChunkGenRequest, generation: u64) {
+        self.requests.push(QueuedChunk {
+            priority: priority(request.0, self.focus),
+            request,
+            generation,
+        });
+    }
+
+    /// Whether `generation` is the live request for `chunk`, rather than one that was
+    /// cancelled or replaced.
+    fn is_current(&self, chunk: Vector3<i32>, generation: u64) -> bool {
+        self.pending.get(&chunk) == Some(&generation)
+    }
+
+    /// Reorders the requests around the current focus, dropping cancelled ones.
+    fn rebuild(&mut self) {
+        let requests = std::mem::take(&mut self.requests).into_vec();
+        for queued in requests {
+            if self.is_current(queued.request.0, queued.generation) {
+                self.push(queued.request, queued.generation);
+            }
+        }
+    }
+
+    /// The nearest request that wasn't cancelled, with its generation.
+    fn pop(&mut self) -> Option<(ChunkGenRequest, u64)> {
+        while let Some(queued) = self.requests.pop() {
+            if self.is_current(queued.request.0, queued.generation) {
+                return Some((queued.request, queued.generation));
+            }
+        }
+        None
+    }
+}
+
+type GeneratedChunk = (Vector3<i32>, u64, MeshData);
+
+type SharedQueue = Arc<(Mutex<Queue>, Condvar)>;
+
+/// Meshes chunks on a pool of worker threads so generation doesn't stall the frame.
+///
+/// Requests wait in a queue until a worker picks them up, nearest to the focus chunk
+/// first, and can be cancelled until then. Finished meshes are collected with `finished`.
+///
+/// Each request gets a new generation, so after a chunk is cancelled and requested again
+/// the mesh from the earlier request is discarded even if a worker was already on it.
+pub struct ChunkGenerator {
+    queue: SharedQueue,
+    results: Receiver<GeneratedChunk>,
+}
+
+impl ChunkGenerator {
+    pub fn new(terrain: SharedTerrain, workers: usize) -> Self {
+        let queue: SharedQueue = Arc::new((Mutex::new(Queue::default()), Condvar::new()));
+        let (sender, results) = channel();
+        for _ in 0..workers.max(1) {
+            let queue = queue.clone();
+            let terrain = terrain.clone();
+            let sender = sender.clone();
+            thread::spawn(move || worker(queue, terrain, sender));
+        }
+        ChunkGenerator { queue, results }
+    }
+
+    /// Queues a chunk for generation, unless it is already pending.
+    pub fn request(&mut self, request: ChunkGenRequest) {
+        let (lock, condvar) = &*self.queue;
+        let mut queue = lock.lock().unwrap();
+        if queue.pending.contains_key(&request.0) {
+            return;
+        }
+        let generation = queue.next_generation;
+        queue.next_generation += 1;
+        queue.pending.insert(request.0, generation);
+        queue.push(request, generation);
+        condvar.notify_one();
+    }
+
+    /// Sets the chunk the queue is ordered around, usually the one the player is in.
+    pub fn set_focus(&mut self, focus: Vector3<i32>) {
+        let (lock, _) = &*self.queue;
+        let mut queue = lock.lock().unwrap();
+        if queue.focus != focus {
+            queue.focus = focus;
+            queue.rebuild();
+        }
+    }
+
+    /// Drops a pending request. It stays queued until a worker reaches it and skips it,
+    /// and a chunk a worker already started on is discarded when it finishes.
+    pub fn cancel(&mut self, chunk: Vector3<i32>) {
+        let (lock, _) = &*self.queue;
+        lock.lock().unwrap().pending.remove(&chunk);
+    }
+
+    pub fn is_pending(&self, chunk: Vector3<i32>) -> bool {
+        let (lock, _) = &*self.queue;
+        lock.lock().unwrap().pending.contains_key(&chunk)
+    }
+
+    /// Chunks requested and not yet finished or cancelled.
+    pub fn pending(&self) -> Vec<Vector3<i32>> {
+        let (lock, _) = &*self.queue;
+        lock.lock().unwrap().pending.keys().copied().collect()
+    }
+
+    /// Meshes finished since the last call, skipping chunks that were cancelled and meshes
+    /// from requests that were since replaced.
+    pub fn finished(&mut self) -> Vec<(Vector3<i32>, MeshData)> {
+        let (lock, _) = &*self.queue;
+        let mut queue = lock.lock().unwrap();
+        let mut finished = vec![];
+        for (chunk, generation, mesh_data) in self.results.try_iter() {
+            if queue.is_current(chunk, generation) {
+                queue.pending.remove(&chunk);
+                finished.push((chunk, mesh_data));
+            }
+        }
+        finished
+    }
+}
+
+impl Drop for ChunkGenerator {
+    fn drop(&mut self) {
+        let (lock, condvar) = &*self.queue;
+        lock.lock().unwrap().closed = true;
+        condvar.notify_all();
+    }
+}
+
+fn worker(queue: SharedQueue, terrain: SharedTerrain, results: Sender<GeneratedChunk>) {
+    let (lock, condvar) = &*queue;
+    // Density buffer reused for every chunk this worker meshes
+    let mut matrix = Matrix3D::new(0, 0, 0);
+    loop {
+        let (request, generation) = {
+            let mut queue = lock.lock().unwrap();
+            loop {
+                if queue.closed {
+                    return;
+                }
+                if let Some(request) = queue.pop() {
+                    break request;
+                }
+                queue = condvar.wait(queue).unwrap();
+            }
+        };
+        let mesh_data = terrain
+            .read()
+            .unwrap()
+            .get_chunk_reusing(request.0, &mut matrix);
+        if results.send((request.0, generation, mesh_data)).is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A queue around the origin holding `chunks`, each requested once.
+    fn queue_of(chunks: &[Vector3<i32>]) -> Queue {
+        let mut queue = Queue::default();
+        for (generation, &chunk) in chunks.iter().enumerate() {
+            queue.pending.insert(chunk, generation as u64);
+            queue.push(ChunkGenRequest(chunk), generation as u64);
+        }
+        queue
+    }
+
+    #[test]
+    fn a_ring_of_chunks_pops_nearest_first() {
+        // Queued farthest first, two chunks at each distance
+        let mut ring = vec![];
+        for radius in (1..=4).rev() {
+            ring.push(Vector3::new(radius, 0, 0));
+            ring.push(Vector3::new(0, 0, -radius));
+        }
+        let mut queue = queue_of(&ring);
+        let mut distances = vec![];
+        while let Some((request, _)) = queue.pop() {
+            distances.push(request.0.dot(&request.0));
+        }
+        assert_eq!(distances, vec![1, 1, 4, 4, 9, 9, 16, 16]);
+    }
+
+    #[test]
+    fn the_column_below_the_focus_comes_first() {
+        let below = Vector3::new(0, -3, 0);
+        let mut queue = queue_of(&[Vector3::new(1, 0, 0), Vector3::new(0, 1, 0), below]);
+        assert_eq!(queue.pop().map(|(request, _)| request.0), Some(below));
+    }
+
+    #[test]
+    fn cancelled_requests_are_skipped() {
+        let (near, far) = (Vector3::new(1, 0, 0), Vector3::new(2, 0, 0));
+        let mut queue = queue_of(&[near, far]);
+        queue.pending.remove(&near);
+        assert_eq!(queue.pop().map(|(request, _)| request.0), Some(far));
+        assert!(queue.pop().is_none());
+    }
+
+    #[test]
+    fn meshes_of_replaced_requests_are_discarded() {
+        // No workers, the test sends the results itself
+        let (sender, results) = channel();
+        let queue = Arc::new((Mutex::new(Queue::default()), Condvar::new()));
+        let mut generator = ChunkGenerator { queue, results };
+        let chunk = Vector3::new(0, 0, 0);
+
+        // Remeshed while a worker was already on the first request
+        generator.request(ChunkGenRequest(chunk));
+        generator.cancel(chunk);
+        generator.request(ChunkGenRequest(chunk));
+        sender.send((chunk, 0, MeshData::default())).unwrap();
+        assert!(generator.finished().is_empty());
+        assert!(generator.is_pending(chunk));
+
+        sender.send((chunk, 1, MeshData::default())).unwrap();
+        assert_eq!(generator.finished().len(), 1);
+        assert!(!generator.is_pending(chunk));
+    }
+}
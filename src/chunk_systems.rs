@@ -0,0 +1,356 @@
+use amethyst::{
+    assets::{AssetStorage, Handle, Loader},
+    shrev::EventChannel,
+    core::{
+        math::{Point3, Vector3},
+        Transform,
+    },
+    ecs::prelude::*,
+    renderer::{mtl::Material, types::Mesh, visibility::BoundingSphere},
+};
+use amethyst_physics::prelude::*;
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    chunk_generation::{ChunkGenRequest, ChunkGenerator},
+    components::*,
+    marching_cubes::MeshData,
+    terrain::{SharedTerrain, Terrain},
+};
+
+const RENDER_DISTANCE: i32 = 5;
+const UNLOAD_MARGIN: i32 = 1;
+
+/// Keeps track of the terrain chunks loaded around the player.
+pub struct ChunkManager {
+    /// Radius, in chunks, of the sphere of chunks kept loaded around the player.
+    pub render_distance: i32,
+    /// Extra chunks past the render distance before a chunk is unloaded, so walking back
+    /// and forth over a chunk border doesn't keep reloading the same chunks.
+    pub unload_margin: i32,
+    material: Handle<Material>,
+    /// Chunks that finished generating, including those without geometry.
+    loaded: HashSet<Vector3<i32>>,
+    /// Entities of the loaded chunks that have any geometry.
+    chunk_entities: HashMap<Vector3<i32>, Entity>,
+    last_update: Option<(Vector3<i32>, i32, i32)>,
+}
+
+impl ChunkManager {
+    pub fn new(material: Handle<Material>) -> Self {
+        ChunkManager {
+            render_distance: RENDER_DISTANCE,
+            unload_margin: UNLOAD_MARGIN,
+            material,
+            loaded: HashSet::new(),
+            chunk_entities: HashMap::new(),
+            last_update: None,
+        }
+    }
+
+    pub fn is_loaded(&self, chunk: Vector3<i32>) -> bool {
+        self.loaded.contains(&chunk)
+    }
+
+    /// The entity drawing a chunk, if the chunk is loaded and has any geometry.
+    pub fn chunk_entity(&self, chunk: Vector3<i32>) -> Option<Entity> {
+        self.chunk_entities.get(&chunk).copied()
+    }
+}
+
+/// Sent after the terrain density was edited, listing the chunks that need new meshes.
+#[derive(Debug, Clone)]
+pub struct TerrainEditEvent {
+    pub affected_chunks: Vec<Vector3<i32>>,
+}
+
+/// Sent by `ChunkManagerSystem` as chunks load and unload, e.g. to decorate chunks or
+/// update a minimap. `Generated` is sent once the chunk's entity was created, so
+/// `ChunkManager::chunk_entity` already returns it; its components are inserted lazily
+/// and present from the next frame. Chunks without geometry are reported too, with no
+/// entity. `Remeshed` is sent instead when a loaded chunk got a new mesh after an edit,
+/// which may have given it an entity or taken it away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkEvent {
+    Generated(Vector3<i32>),
+    Remeshed(Vector3<i32>),
+    Unloaded(Vector3<i32>),
+}
+
+fn distance_squared(a: Vector3<i32>, b: Vector3<i32>) -> i64 {
+    let d = a.map(i64::from) - b.map(i64::from);
+    d.dot(&d)
+}
+
+/// Marks a chunk whose mesh finished as loaded, returning the event announcing it:
+/// `Generated` the first time, `Remeshed` while it stays loaded.
+fn finish_loading(loaded: &mut HashSet<Vector3<i32>>, chunk: Vector3<i32>) -> ChunkEvent {
+    if loaded.insert(chunk) {
+        ChunkEvent::Generated(chunk)
+    } else {
+        ChunkEvent::Remeshed(chunk)
+    }
+}
+
+/// Requests chunks within the render distance of the character from the `ChunkGenerator`,
+/// spawns them as they finish and unloads the ones that drifted out of range.
+#[derive(Default)]
+pub struct ChunkManagerSystem;
+
+impl<'s> System<'s> for ChunkManagerSystem {
+    #[allow(clippy::type_complexity)]
+    type SystemData = (
+        Entities<'s>,
+        Read<'s, LazyUpdate>,
+        WriteExpect<'s, ChunkManager>,
+        WriteExpect<'s, ChunkGenerator>,
+        ReadExpect<'s, SharedTerrain>,
+        ReadExpect<'s, PhysicsWorld<f32>>,
+        ReadExpect<'s, Loader>,
+        Read<'s, AssetStorage<Mesh>>,
+        Write<'s, EventChannel<ChunkEvent>>,
+        ReadStorage<'s, CharacterBody>,
+        ReadStorage<'s, Transform>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            entities,
+            lazy,
+            mut manager,
+            mut generator,
+            terrain,
+            physics_world,
+            loader,
+            mesh_storage,
+            mut chunk_events,
+            character_bodies,
+            transforms,
+        ): Self::SystemData,
+    ) {
+        let terrain = terrain.read().unwrap();
+        let player_chunk = (&transforms, &character_bodies)
+            .join()
+            .next()
+            .map(|(transform, _)| terrain.chunk_at(transform.translation()));
+        if let Some(player_chunk) = player_chunk {
+            let update = (player_chunk, manager.render_distance, manager.unload_margin);
+            if manager.last_update != Some(update) {
+                manager.last_update = Some(update);
+                update_loaded(
+                    &entities,
+                    &mut manager,
+                    &mut generator,
+                    &mut chunk_events,
+                    &terrain,
+                    player_chunk,
+                );
+            }
+        }
+
+        for (chunk, mesh_data) in generator.finished() {
+            if let Some(entity) = manager.chunk_entities.get(&chunk).copied() {
+                // A remeshed chunk, swap the mesh and collider of the existing entity
+                let shape_desc = mesh_data.to_trimesh_shape();
+                let bounds = bounding_sphere(&mesh_data);
+                match (mesh_data.into_mesh(&loader, &mesh_storage), shape_desc) {
+                    (Some(mesh), Some(shape_desc)) => {
+                        lazy.insert(entity, mesh);
+                        lazy.insert(entity, bounds);
+                        lazy.insert(entity, physics_world.shape_server().create(&shape_desc));
+                    }
+                    _ => {
+                        let _ = entities.delete(entity);
+                        manager.chunk_entities.remove(&chunk);
+                    }
+                }
+            } else {
+                let entity = spawn_chunk(
+                    &entities,
+                    &lazy,
+                    &terrain,
+                    &physics_world,
+                    &loader,
+                    &mesh_storage,
+                    manager.material.clone(),
+                    chunk,
+                    mesh_data,
+                );
+                if let Some(entity) = entity {
+                    manager.chunk_entities.insert(chunk, entity);
+                }
+            }
+            chunk_events.single_write(finish_loading(&mut manager.loaded, chunk));
+        }
+    }
+}
+
+/// Regenerates the meshes of loaded chunks named by `TerrainEditEvent`s on the chunk
+/// generator, coalescing repeated edits to a chunk into a single remesh. Edits to chunks
+/// that aren't loaded are already stored in the terrain and show up when they load.
+#[derive(Default)]
+pub struct ChunkRemeshSystem {
+    edit_reader: Option<ReaderId<TerrainEditEvent>>,
+}
+
+impl<'s> System<'s> for ChunkRemeshSystem {
+    type SystemData = (
+        Read<'s, EventChannel<TerrainEditEvent>>,
+        ReadExpect<'s, ChunkManager>,
+        WriteExpect<'s, ChunkGenerator>,
+    );
+
+    fn run(&mut self, (edit_events, manager, mut generator): Self::SystemData) {
+        let mut dirty = HashSet::new();
+        for event in edit_events.read(self.edit_reader.as_mut().unwrap()) {
+            dirty.extend(event.affected_chunks.iter().copied());
+        }
+        for chunk in dirty {
+            if manager.is_loaded(chunk) {
+                generator.cancel(chunk);
+                generator.request(ChunkGenRequest(chunk));
+            }
+        }
+    }
+
+    fn setup(&mut self, world: &mut World) {
+        Self::SystemData::setup(world);
+        let mut channel = world.fetch_mut::<EventChannel<TerrainEditEvent>>();
+        self.edit_reader = Some(channel.register_reader());
+    }
+}
+
+fn update_loaded(
+    entities: &Entities<'_>,
+    manager: &mut ChunkManager,
+    generator: &mut ChunkGenerator,
+    chunk_events: &mut EventChannel<ChunkEvent>,
+    terrain: &Terrain,
+    player_chunk: Vector3<i32>,
+) {
+    generator.set_focus(player_chunk);
+
+    // Unload the chunks that are now too far away, and cancel those not generated yet
+    let keep = i64::from(manager.render_distance + manager.unload_margin);
+    let is_far = |chunk: &Vector3<i32>| distance_squared(*chunk, player_chunk) > keep * keep;
+    let far: Vec<Vector3<i32>> = manager.loaded.iter().filter(|c| is_far(c)).copied().collect();
+    for chunk in far {
+        manager.loaded.remove(&chunk);
+        if let Some(entity) = manager.chunk_entities.remove(&chunk) {
+            // Deleting the entity drops its physics handles, which frees the body and shape.
+            let _ = entities.delete(entity);
+        }
+        chunk_events.single_write(ChunkEvent::Unloaded(chunk));
+    }
+    let far: Vec<Vector3<i32>> = generator.pending().into_iter().filter(is_far).collect();
+    for chunk in far {
+        generator.cancel(chunk);
+    }
+
+    // Request the missing chunks within the render distance
+    let radius = manager.render_distance;
+    for z in -radius..=radius {
+        for y in -radius..=radius {
+            for x in -radius..=radius {
+                let chunk = player_chunk + Vector3::new(x, y, z);
+                if distance_squared(chunk, player_chunk) > i64::from(radius) * i64::from(radius) {
+                    continue;
+                }
+                if !manager.is_loaded(chunk) && terrain.chunk_in_bounds(chunk) {
+                    generator.request(ChunkGenRequest(chunk));
+                }
+            }
+        }
+    }
+}
+
+/// Creates the entity for a chunk, with its mesh and a static trimesh collider.
+/// Returns `None` without creating anything if the chunk has no geometry.
+#[allow(clippy::too_many_arguments)]
+pub fn spawn_chunk(
+    entities: &Entities<'_>,
+    lazy: &LazyUpdate,
+    terrain: &Terrain,
+    physics_world: &PhysicsWorld<f32>,
+    loader: &Loader,
+    mesh_storage: &AssetStorage<Mesh>,
+    material: Handle<Material>,
+    chunk: Vector3<i32>,
+    mesh_data: MeshData,
+) -> Option<Entity> {
+    let shape_desc = mesh_data.to_trimesh_shape()?;
+    let bounds = bounding_sphere(&mesh_data);
+    let mesh = mesh_data.into_mesh(loader, mesh_storage)?;
+
+    let rb = {
+        let mut rb_desc = RigidBodyDesc::default();
+        rb_desc.mode = BodyMode::Static;
+        physics_world.rigid_body_server().create(&rb_desc)
+    };
+    let shape = physics_world.shape_server().create(&shape_desc);
+
+    let mut transform = Transform::default();
+    transform.set_translation(terrain.chunk_origin(chunk));
+
+    Some(
+        lazy.create_entity(entities)
+            .with(mesh)
+            .with(material)
+            .with(bounds)
+            .with(transform)
+            .with(shape)
+            .with(rb)
+            .with(Chunk)
+            .build(),
+    )
+}
+
+/// Sphere around the vertices of a chunk mesh, in chunk space, for frustum culling.
+fn bounding_sphere(mesh_data: &MeshData) -> BoundingSphere {
+    let (min, max) = mesh_data.stats().bounds;
+    BoundingSphere::new(Point3::from((min + max) / 2.0), (max - min).norm() / 2.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::terrain::TerrainBuilder;
+    use std::{
+        sync::{Arc, RwLock},
+        thread,
+        time::Duration,
+    };
+
+    /// Waits for the generator to mesh the requested chunks.
+    fn wait_for(generator: &mut ChunkGenerator) -> Vec<(Vector3<i32>, MeshData)> {
+        for _ in 0..500 {
+            let finished = generator.finished();
+            if !finished.is_empty() {
+                return finished;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("the chunk never finished");
+    }
+
+    #[test]
+    fn readers_hear_about_generated_and_remeshed_chunks() {
+        let terrain = TerrainBuilder::new(1u64).with_points_per_chunk(8).build().unwrap();
+        let mut generator = ChunkGenerator::new(Arc::new(RwLock::new(terrain)), 1);
+        let mut channel = EventChannel::<ChunkEvent>::new();
+        let mut reader = channel.register_reader();
+        let mut loaded = HashSet::new();
+        let chunk = Vector3::new(0, 0, 0);
+
+        // Loaded once, then remeshed after an edit
+        for _ in 0..2 {
+            generator.request(ChunkGenRequest(chunk));
+            for (chunk, _) in wait_for(&mut generator) {
+                channel.single_write(finish_loading(&mut loaded, chunk));
+            }
+        }
+        let events: Vec<ChunkEvent> = channel.read(&mut reader).copied().collect();
+        assert_eq!(events, [ChunkEvent::Generated(chunk), ChunkEvent::Remeshed(chunk)]);
+    }
+}
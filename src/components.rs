@@ -1,19 +1,91 @@
-use amethyst::ecs::{storage::NullStorage, Component};
+use amethyst::{
+    core::math::{UnitQuaternion, Vector2, Vector3},
+    ecs::{storage::DenseVecStorage, storage::NullStorage, Component},
+};
+use amethyst_physics::prelude::ShapeDesc;
 
-/// Camera Boom handle tag, used to identify the camera boom handle entity
-#[derive(Default)]
-pub struct CameraBoomHandle;
+/// Camera Boom handle, used to identify the camera boom handle entity
+pub struct CameraBoomHandle {
+    /// Current distance of the camera behind the boom, 0 for first person. Eases towards
+    /// `target_length` as the player zooms.
+    pub length: f32,
+    /// Distance the scroll wheel zoomed to, within `min_length` and `max_length`.
+    pub target_length: f32,
+    pub min_length: f32,
+    pub max_length: f32,
+    /// How quickly `length` catches up with `target_length`, per second.
+    pub zoom_smoothing: f32,
+}
+
+impl Default for CameraBoomHandle {
+    fn default() -> Self {
+        CameraBoomHandle {
+            length: 0.0,
+            target_length: 0.0,
+            min_length: 0.0,
+            max_length: 15.0,
+            zoom_smoothing: 12.0,
+        }
+    }
+}
 
 impl Component for CameraBoomHandle {
-    type Storage = NullStorage<Self>;
+    type Storage = DenseVecStorage<Self>;
+}
+
+/// Look direction of a camera boom. The boom's rotation is rebuilt from it every frame as
+/// yaw then pitch, so it never rolls.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CameraOrientation {
+    /// Rotation around the boom's X axis in radians, positive looks up.
+    pub pitch: f32,
+    /// Rotation around the world Y axis in radians.
+    pub yaw: f32,
+}
+
+impl CameraOrientation {
+    /// Adds a pitch and yaw `delta` in radians, keeping the pitch within `max_pitch` of
+    /// level.
+    pub fn turn(&mut self, delta: Vector2<f32>, max_pitch: f32) {
+        self.pitch = (self.pitch + delta.x).max(-max_pitch).min(max_pitch);
+        self.yaw = (self.yaw + delta.y) % (std::f32::consts::PI * 2.0);
+    }
+
+    pub fn rotation(&self) -> UnitQuaternion<f32> {
+        UnitQuaternion::from_axis_angle(&Vector3::y_axis(), self.yaw)
+            * UnitQuaternion::from_axis_angle(&Vector3::x_axis(), self.pitch)
+    }
+}
+
+impl Component for CameraOrientation {
+    type Storage = DenseVecStorage<Self>;
 }
 
-/// Tag used to identify the character body entity.
+/// How a character body is currently moving.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MovementMode {
+    Walking,
+    Swimming,
+    Flying,
+}
+
+impl Default for MovementMode {
+    fn default() -> Self {
+        MovementMode::Walking
+    }
+}
+
+/// Identifies the character body entity, with the way it moves for animation and audio.
 #[derive(Default)]
-pub struct CharacterBody;
+pub struct CharacterBody {
+    pub mode: MovementMode,
+    /// Whether the collider is shrunk to the crouched capsule. Stays set while Crouch is
+    /// released under a low ceiling, until there's room to stand.
+    pub crouched: bool,
+}
 
 impl Component for CharacterBody {
-    type Storage = NullStorage<Self>;
+    type Storage = DenseVecStorage<Self>;
 }
 
 /// Tag used to identify Chunk entities
@@ -23,3 +95,223 @@ pub struct Chunk;
 impl Component for Chunk {
     type Storage = NullStorage<Self>;
 }
+
+/// Whether the character is standing on something, updated from its physics contacts.
+pub struct GroundContact {
+    pub grounded: bool,
+    /// Seconds since the character was last grounded, infinite once a jump has left it.
+    pub air_time: f32,
+    /// Normal of the terrain below the character, straight up if there's none in reach.
+    pub normal: Vector3<f32>,
+}
+
+impl Default for GroundContact {
+    fn default() -> Self {
+        GroundContact {
+            grounded: false,
+            air_time: 0.0,
+            normal: Vector3::y(),
+        }
+    }
+}
+
+impl Component for GroundContact {
+    type Storage = DenseVecStorage<Self>;
+}
+
+/// Movement tuning for a character body, read by the character motion controller.
+#[derive(Debug, Clone)]
+pub struct MovementConfig {
+    /// Horizontal speed gained per second while moving towards the input direction.
+    pub acceleration: f32,
+    /// Horizontal speed lost per second on the ground without movement input.
+    pub friction: f32,
+    /// Half height of the cylinder of the character's capsule while standing.
+    pub stand_half_height: f32,
+    /// Half height of the capsule's cylinder while crouched.
+    pub crouch_half_height: f32,
+    pub capsule_radius: f32,
+    /// How quickly the camera follows the eye height as the character crouches and
+    /// stands, per second.
+    pub crouch_camera_smoothing: f32,
+    /// Upward velocity given by a jump.
+    pub max_jump_velocity: f32,
+    /// Upward velocity a jump is cut down to when the button is released early.
+    pub min_jump_velocity: f32,
+    /// Seconds after a jump starts during which holding Jump keeps pushing up.
+    pub jump_hold_time: f32,
+    /// Upward force of holding Jump during `jump_hold_time`.
+    pub jump_hold_force: f32,
+    /// Seconds after a jump before the next one can start.
+    pub jump_cooldown: f32,
+    /// Holding Jump jumps again on landing instead of needing a new press.
+    pub bunny_hop: bool,
+    /// Seconds after leaving the ground during which a jump is still allowed.
+    pub coyote_time: f32,
+    /// Seconds a Jump press is remembered, so pressing it just before landing jumps.
+    pub jump_buffer_time: f32,
+    pub sprint_multiplier: f32,
+    pub crouch_multiplier: f32,
+    /// Speed, in units per second, while flying with noclip.
+    pub fly_speed: f32,
+    /// Horizontal speed, in units per second, of full movement input. The sprint, crouch
+    /// and swim multipliers scale it.
+    pub max_speed: f32,
+    /// Share of the acceleration available while in the air.
+    pub air_control: f32,
+    /// Share of the friction applied while in the air, so jumps keep their momentum.
+    pub air_braking: f32,
+    /// Steepest ground, in degrees from flat, the character can walk up.
+    pub max_slope: f32,
+    /// Downhill force on ground steeper than `max_slope`, 0 to stand still on it.
+    pub slide_force: f32,
+    /// Upward force per unit of the body below the water surface.
+    pub buoyancy: f32,
+    /// Upward or downward force of holding Jump or Crouch while swimming.
+    pub swim_force: f32,
+    /// Share of the walking speed kept while swimming.
+    pub swim_multiplier: f32,
+    /// Vertical force per unit of velocity that slows the body down in water.
+    pub water_drag: f32,
+}
+
+impl Default for MovementConfig {
+    fn default() -> Self {
+        MovementConfig {
+            acceleration: 40.0,
+            friction: 30.0,
+            stand_half_height: 0.75,
+            crouch_half_height: 0.25,
+            capsule_radius: 0.5,
+            crouch_camera_smoothing: 10.0,
+            max_jump_velocity: 5.0,
+            min_jump_velocity: 2.5,
+            jump_hold_time: 0.25,
+            jump_hold_force: 8.0,
+            jump_cooldown: 0.2,
+            bunny_hop: false,
+            coyote_time: 0.1,
+            jump_buffer_time: 0.15,
+            sprint_multiplier: 3.0,
+            crouch_multiplier: 0.4,
+            fly_speed: 20.0,
+            max_speed: 4.0,
+            air_control: 0.05,
+            air_braking: 0.05,
+            max_slope: 45.0,
+            slide_force: 100.0,
+            buoyancy: 6.0,
+            swim_force: 12.0,
+            swim_multiplier: 0.5,
+            water_drag: 4.0,
+        }
+    }
+}
+
+impl MovementConfig {
+    /// Half height of the capsule's cylinder, standing or crouched.
+    pub fn half_height(&self, crouched: bool) -> f32 {
+        if crouched {
+            self.crouch_half_height
+        } else {
+            self.stand_half_height
+        }
+    }
+
+    /// Collider of the character, standing or crouched.
+    pub fn capsule(&self, crouched: bool) -> ShapeDesc<f32> {
+        ShapeDesc::Capsule {
+            half_height: self.half_height(crouched),
+            radius: self.capsule_radius,
+        }
+    }
+}
+
+impl Component for MovementConfig {
+    type Storage = DenseVecStorage<Self>;
+}
+
+/// Stamina pool that limits sprinting. Drains while sprinting and regenerates otherwise.
+#[derive(Debug, Clone)]
+pub struct Stamina {
+    pub current: f32,
+    pub max: f32,
+    /// Stamina used per second of sprinting.
+    pub drain_rate: f32,
+    /// Stamina regained per second while not sprinting.
+    pub regen_rate: f32,
+    /// Stamina needed to sprint again after running out.
+    pub recover_threshold: f32,
+    /// Set when stamina runs out, until it regenerates past `recover_threshold`.
+    pub exhausted: bool,
+}
+
+impl Stamina {
+    /// Share of the pool left, in [0, 1], e.g. for a stamina bar.
+    pub fn fraction(&self) -> f32 {
+        if self.max <= 0.0 {
+            return 0.0;
+        }
+        return (self.current / self.max).max(0.0).min(1.0);
+    }
+
+    /// Drains stamina over `delta_seconds` of sprinting, or regenerates it if
+    /// `sprinting` is false. Returns whether the sprint multiplier should apply.
+    pub fn update(&mut self, sprinting: bool, delta_seconds: f32) -> bool {
+        let sprint = sprinting && !self.exhausted && self.current > 0.0;
+        if sprint {
+            self.current = (self.current - self.drain_rate * delta_seconds).max(0.0);
+            if self.current <= 0.0 {
+                self.exhausted = true;
+            }
+        } else {
+            self.current = (self.current + self.regen_rate * delta_seconds).min(self.max);
+            if self.exhausted && self.current >= self.recover_threshold {
+                self.exhausted = false;
+            }
+        }
+        return sprint;
+    }
+}
+
+impl Default for Stamina {
+    fn default() -> Self {
+        Stamina {
+            current: 5.0,
+            max: 5.0,
+            drain_rate: 1.0,
+            regen_rate: 0.5,
+            recover_threshold: 1.5,
+            exhausted: false,
+        }
+    }
+}
+
+impl Component for Stamina {
+    type Storage = DenseVecStorage<Self>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DT: f32 = 1.0 / 60.0;
+
+    #[test]
+    fn sprinting_long_enough_stops_the_sprint() {
+        let mut stamina = Stamina::default();
+        assert!(stamina.update(true, DT));
+        // Five seconds of stamina at one per second
+        let sprinted = (0..330).filter(|_| stamina.update(true, DT)).count();
+        assert!((295..=300).contains(&sprinted));
+        assert!(stamina.exhausted);
+        assert!(!stamina.update(true, DT));
+        // Holding sprint while exhausted regenerates, but only sprints again past the
+        // threshold
+        for _ in 0..120 {
+            assert!(!stamina.update(true, DT));
+        }
+        assert!((0..60).any(|_| stamina.update(true, DT)));
+        assert!(!stamina.exhausted);
+    }
+}
@@ -0,0 +1,93 @@
+use amethyst::input::{BindingError, Bindings, StringBindings};
+use std::{error::Error, fmt, fs, io, path::Path};
+
+// Action names, as used in the bindings files
+pub const FORWARD: &str = "Forward";
+pub const BACKWARD: &str = "Backward";
+pub const RIGHT: &str = "Right";
+pub const LEFT: &str = "Left";
+pub const JUMP: &str = "Jump";
+pub const SPRINT: &str = "Sprint";
+pub const CROUCH: &str = "Crouch";
+pub const TOGGLE_FLY: &str = "ToggleFly";
+pub const DIG: &str = "Dig";
+pub const PLACE: &str = "Place";
+
+// Axis names, as used in the bindings files
+pub const MOVE_X: &str = "MoveX";
+pub const MOVE_Y: &str = "MoveY";
+pub const LOOK_X: &str = "LookX";
+pub const LOOK_Y: &str = "LookY";
+
+/// Reads input bindings from a RON file in the format of `assets/input_bindings.ron`,
+/// checking that no two actions or axes share a button.
+pub fn load_bindings(path: impl AsRef<Path>) -> Result<Bindings<StringBindings>, BindingsError> {
+    let source = fs::read_to_string(path)?;
+    return parse_bindings(&source);
+}
+
+/// Like `load_bindings`, reading the RON from a string.
+pub fn parse_bindings(source: &str) -> Result<Bindings<StringBindings>, BindingsError> {
+    let mut bindings: Bindings<StringBindings> = ron::from_str(source)?;
+    bindings.check_invariants()?;
+    return Ok(bindings);
+}
+
+/// Loads the player's bindings from `user_path` when that file exists, so keys can be
+/// remapped without touching the defaults, and the bindings at `default_path` otherwise.
+pub fn load_bindings_or_default(
+    user_path: impl AsRef<Path>,
+    default_path: impl AsRef<Path>,
+) -> Result<Bindings<StringBindings>, BindingsError> {
+    if user_path.as_ref().exists() {
+        return load_bindings(user_path);
+    }
+    return load_bindings(default_path);
+}
+
+/// Reasons a bindings file can't be loaded.
+#[derive(Debug)]
+pub enum BindingsError {
+    Io(io::Error),
+    Parse(ron::Error),
+    /// The same button is bound twice.
+    Conflict(BindingError<StringBindings>),
+}
+
+impl fmt::Display for BindingsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BindingsError::Io(e) => write!(f, "couldn't read input bindings: {}", e),
+            BindingsError::Parse(e) => write!(f, "couldn't parse input bindings: {}", e),
+            BindingsError::Conflict(e) => write!(f, "invalid input bindings: {}", e),
+        }
+    }
+}
+
+impl Error for BindingsError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            BindingsError::Io(e) => Some(e),
+            BindingsError::Parse(e) => Some(e),
+            BindingsError::Conflict(e) => Some(e),
+        }
+    }
+}
+
+impl From<io::Error> for BindingsError {
+    fn from(e: io::Error) -> Self {
+        BindingsError::Io(e)
+    }
+}
+
+impl From<ron::Error> for BindingsError {
+    fn from(e: ron::Error) -> Self {
+        BindingsError::Parse(e)
+    }
+}
+
+impl From<BindingError<StringBindings>> for BindingsError {
+    fn from(e: BindingError<StringBindings>) -> Self {
+        BindingsError::Conflict(e)
+    }
+}
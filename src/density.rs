@@ -0,0 +1,377 @@
+use amethyst::core::math::Vector3;
+use splines::Spline;
+
+use crate::terrain::NoiseLayer;
+
+/// A density function the terrain is meshed from. Densities below the terrain cutoff are
+/// solid, higher ones are air.
+pub trait DensityField: Send + Sync {
+    fn density(&self, world_pos: Vector3<f32>) -> f32;
+
+    /// Smallest and largest density the field can return at a height, if known. Used to
+    /// skip chunks that are entirely solid or entirely air without sampling them.
+    fn density_range(&self, _y: f32) -> Option<(f32, f32)> {
+        None
+    }
+}
+
+impl<F> DensityField for F
+where
+    F: Fn(Vector3<f32>) -> f32 + Send + Sync,
+{
+    fn density(&self, world_pos: Vector3<f32>) -> f32 {
+        self(world_pos)
+    }
+}
+
+/// The vertical density profile of one kind of landscape: the density bounds at each
+/// height and how strongly each noise layer shows through.
+pub struct BiomeProfile {
+    pub upper_bound: Spline<f32, f32>,
+    pub lower_bound: Spline<f32, f32>,
+    /// One weight per noise layer of the generator.
+    pub noise_weights: Vec<f32>,
+}
+
+impl BiomeProfile {
+    pub fn new(
+        upper_bound: Spline<f32, f32>,
+        lower_bound: Spline<f32, f32>,
+        noise_weights: Vec<f32>,
+    ) -> Self {
+        BiomeProfile {
+            upper_bound,
+            lower_bound,
+            noise_weights,
+        }
+    }
+
+    fn bounds(&self, y: f32) -> (f32, f32) {
+        let upper_bound = self.upper_bound.clamped_sample(y).unwrap();
+        let lower_bound = self.lower_bound.clamped_sample(y).unwrap();
+        (upper_bound, lower_bound)
+    }
+
+    /// The density at height `y` for `val`, the noise layers summed by this biome's
+    /// weights.
+    fn density(&self, val: f32, y: f32) -> f32 {
+        let (upper_bound, lower_bound) = self.bounds(y);
+        let diff = upper_bound - lower_bound;
+        (val - (-1.0)) * 0.5 * diff + lower_bound
+    }
+
+    /// `noise_bounds` holds the largest magnitude of each noise layer, `None` for a
+    /// layer without a known bound, which leaves the range unknown unless its weight is 0.
+    fn density_range(&self, y: f32, noise_bounds: &[Option<f32>]) -> Option<(f32, f32)> {
+        let mut weight = 0.0;
+        for (w, bound) in self.noise_weights.iter().zip(noise_bounds) {
+            if *w != 0.0 {
+                weight += w.abs() * (*bound)?;
+            }
+        }
+        let (upper_bound, lower_bound) = self.bounds(y);
+        let diff = upper_bound - lower_bound;
+        Some((
+            (1.0 - weight) * 0.5 * diff + lower_bound,
+            (1.0 + weight) * 0.5 * diff + lower_bound,
+        ))
+    }
+}
+
+/// Weighted noise layers blended between an upper and a lower density bound that
+/// depend on the height.
+///
+/// With several biomes, a 2D biome noise over x and z picks where each biome's profile
+/// applies, and densities are blended between neighbouring biomes so their borders are
+/// smooth slopes rather than cliffs.
+pub struct DefaultGenerator {
+    noise: Vec<NoiseLayer>,
+    noise_scales: Vec<f32>,
+    /// Largest magnitude of each noise layer, if known.
+    noise_bounds: Vec<Option<f32>>,
+    biomes: Vec<BiomeProfile>,
+    biome_noise: Option<(NoiseLayer, f32)>,
+}
+
+impl DefaultGenerator {
+    pub fn new(
+        noise: Vec<NoiseLayer>,
+        noise_weights: Vec<f32>,
+        noise_scales: Vec<f32>,
+        upper_bound: Spline<f32, f32>,
+        lower_bound: Spline<f32, f32>,
+    ) -> Self {
+        DefaultGenerator {
+            noise_bounds: vec![Some(1.0); noise.len()],
+            noise,
+            noise_scales,
+            biomes: vec![BiomeProfile::new(upper_bound, lower_bound, noise_weights)],
+            biome_noise: None,
+        }
+    }
+
+    /// Blends several biomes, in order, by `biome_noise` sampled at `biome_scale` over x
+    /// and z. Every biome needs a weight for each noise layer.
+    pub fn with_biomes(
+        noise: Vec<NoiseLayer>,
+        noise_scales: Vec<f32>,
+        biomes: Vec<BiomeProfile>,
+        biome_noise: NoiseLayer,
+        biome_scale: f32,
+    ) -> Self {
+        DefaultGenerator {
+            noise_bounds: vec![Some(1.0); noise.len()],
+            noise,
+            noise_scales,
+            biomes,
+            biome_noise: Some((biome_noise, biome_scale)),
+        }
+    }
+
+    /// Replaces the largest magnitude of each noise layer, `None` for a layer without a
+    /// known bound. Layers are assumed to stay within [-1, 1] otherwise.
+    pub fn with_noise_bounds(mut self, noise_bounds: Vec<Option<f32>>) -> Self {
+        self.noise_bounds = noise_bounds;
+        self
+    }
+
+    /// Adds a noise layer within [-1, 1], such as a `FractalNoise`, sampled at `scale` and
+    /// weighted by `weight` in every biome.
+    pub fn add_noise_layer(&mut self, layer: NoiseLayer, weight: f32, scale: f32) {
+        self.noise.push(layer);
+        self.noise_scales.push(scale);
+        self.noise_bounds.push(Some(1.0));
+        for biome in &mut self.biomes {
+            biome.noise_weights.push(weight);
+        }
+    }
+
+    /// The two biomes at a world XZ position and how far to blend from the first to the
+    /// second.
+    fn biome_blend(&self, x: f32, z: f32) -> (usize, usize, f32) {
+        let last = self.biomes.len() - 1;
+        let (noise, scale) = match &self.biome_noise {
+            Some(biome_noise) if last > 0 => biome_noise,
+            _ => return (0, 0, 0.0),
+        };
+        let val = noise.get([(x * scale) as f64, 0.0, (z * scale) as f64]) as f32;
+        let t = ((val + 1.0) * 0.5).max(0.0).min(1.0) * last as f32;
+        let first = (t.floor() as usize).min(last - 1);
+        let frac = t - first as f32;
+        // Smoothstep, so the blend has no kink where one biome pair hands over to the next
+        (first, first + 1, frac * frac * (3.0 - 2.0 * frac))
+    }
+}
+
+impl DensityField for DefaultGenerator {
+    fn density(&self, world_pos: Vector3<f32>) -> f32 {
+        let (first, second, blend) = self.biome_blend(world_pos.x, world_pos.z);
+        // Weigh each sample for both biomes as it's taken, rather than collecting them
+        let mut first_val = 0.0;
+        let mut second_val = 0.0;
+        let layers = self.noise.iter().zip(&self.noise_scales);
+        let weights = self.biomes[first]
+            .noise_weights
+            .iter()
+            .zip(&self.biomes[second].noise_weights);
+        for ((layer, scale), (first_weight, second_weight)) in layers.zip(weights) {
+            let sample = layer.get([
+                (world_pos.x * scale) as f64,
+                (world_pos.y * scale) as f64,
+                (world_pos.z * scale) as f64,
+            ]) as f32;
+            first_val += sample * first_weight;
+            second_val += sample * second_weight;
+        }
+
+        let density = self.biomes[first].density(first_val, world_pos.y);
+        if blend <= 0.0 {
+            return density;
+        }
+        let other = self.biomes[second].density(second_val, world_pos.y);
+        density + (other - density) * blend
+    }
+
+    /// Blending stays between the biome densities, so the range covers every biome.
+    fn density_range(&self, y: f32) -> Option<(f32, f32)> {
+        let mut range: Option<(f32, f32)> = None;
+        for biome in &self.biomes {
+            let (min, max) = biome.density_range(y, &self.noise_bounds)?;
+            range = Some(match range {
+                Some((lo, hi)) => (min.min(lo), max.max(hi)),
+                None => (min, max),
+            });
+        }
+        range
+    }
+}
+
+/// Offsets every sample position by another noise field before evaluating a density
+/// field, turning blobby noise into ridges and valleys.
+pub struct DomainWarp {
+    inner: Box<dyn DensityField>,
+    /// One noise per axis.
+    noise: [NoiseLayer; 3],
+    amplitude: f32,
+    frequency: f32,
+}
+
+impl DomainWarp {
+    /// Warps `inner` by up to `amplitude` world units, with the offset changing at
+    /// `frequency`.
+    pub fn new(
+        inner: Box<dyn DensityField>,
+        noise: [NoiseLayer; 3],
+        amplitude: f32,
+        frequency: f32,
+    ) -> Self {
+        DomainWarp {
+            inner,
+            noise,
+            amplitude,
+            frequency,
+        }
+    }
+
+    /// The position `world_pos` samples the inner field at.
+    pub fn warp(&self, world_pos: Vector3<f32>) -> Vector3<f32> {
+        let p = [
+            (world_pos.x * self.frequency) as f64,
+            (world_pos.y * self.frequency) as f64,
+            (world_pos.z * self.frequency) as f64,
+        ];
+        let offset = Vector3::new(
+            self.noise[0].get(p) as f32,
+            self.noise[1].get(p) as f32,
+            self.noise[2].get(p) as f32,
+        );
+        world_pos + offset * self.amplitude
+    }
+}
+
+impl DensityField for DomainWarp {
+    fn density(&self, world_pos: Vector3<f32>) -> f32 {
+        self.inner.density(self.warp(world_pos))
+    }
+
+    /// A row can sample the inner field up to `amplitude` above or below it, so this is
+    /// the inner range over those heights, checked one unit apart.
+    fn density_range(&self, y: f32) -> Option<(f32, f32)> {
+        let amplitude = self.amplitude.abs();
+        let steps = amplitude.ceil().max(1.0) as usize;
+        let mut range: Option<(f32, f32)> = None;
+        for i in 0..=(2 * steps) {
+            let offset = -amplitude + amplitude * i as f32 / steps as f32;
+            let (min, max) = self.inner.density_range(y + offset)?;
+            range = Some(match range {
+                Some((lo, hi)) => (min.min(lo), max.max(hi)),
+                None => (min, max),
+            });
+        }
+        range
+    }
+}
+
+/// Shape of the tunnels carved by `CaveCarver`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CaveSettings {
+    /// Frequency of the two tunnel noises, lower gives longer tunnels.
+    pub frequency: f32,
+    /// How close to zero both noises have to be to carve, wider gives thicker tunnels.
+    pub radius: f32,
+    /// Density added at the middle of a tunnel, 2 opens any solid terrain.
+    pub strength: f32,
+    /// Height above which no tunnels are carved.
+    pub max_height: f32,
+    /// Distance below `max_height` over which tunnels fade in to full strength.
+    pub falloff: f32,
+}
+
+impl Default for CaveSettings {
+    fn default() -> Self {
+        CaveSettings {
+            frequency: 0.03,
+            radius: 0.08,
+            strength: 2.0,
+            max_height: -5.0,
+            falloff: 10.0,
+        }
+    }
+}
+
+/// Carves connected tunnels into a density field where two noise fields are both close to
+/// zero. Each noise is near zero on a winding sheet, and two sheets meet along a winding
+/// line, so the carved space forms a tunnel network rather than open caverns.
+pub struct CaveCarver {
+    inner: Box<dyn DensityField>,
+    noise: [NoiseLayer; 2],
+    settings: CaveSettings,
+}
+
+impl CaveCarver {
+    pub fn new(
+        inner: Box<dyn DensityField>,
+        noise: [NoiseLayer; 2],
+        settings: CaveSettings,
+    ) -> Self {
+        CaveCarver {
+            inner,
+            noise,
+            settings,
+        }
+    }
+
+    /// Share of the full tunnel strength allowed at a height, fading out towards
+    /// `max_height`.
+    fn depth_factor(&self, y: f32) -> f32 {
+        let below = self.settings.max_height - y;
+        if below <= 0.0 {
+            return 0.0;
+        }
+        if self.settings.falloff <= 0.0 {
+            return 1.0;
+        }
+        let t = (below / self.settings.falloff).min(1.0);
+        t * t * (3.0 - 2.0 * t)
+    }
+}
+
+impl DensityField for CaveCarver {
+    fn density(&self, world_pos: Vector3<f32>) -> f32 {
+        let density = self.inner.density(world_pos);
+        let depth = self.depth_factor(world_pos.y);
+        if depth <= 0.0 || self.settings.radius <= 0.0 {
+            return density;
+        }
+        let f = self.settings.frequency;
+        let p = [
+            (world_pos.x * f) as f64,
+            (world_pos.y * f) as f64,
+            (world_pos.z * f) as f64,
+        ];
+        let ridge = self.noise[0].get(p).abs().max(self.noise[1].get(p).abs()) as f32;
+        let tunnel = (1.0 - ridge / self.settings.radius).max(0.0);
+        density + tunnel * depth * self.settings.strength
+    }
+
+    /// Carving only ever adds density, by at most the strength allowed at the height.
+    fn density_range(&self, y: f32) -> Option<(f32, f32)> {
+        let (min, max) = self.inner.density_range(y)?;
+        Some((min, max + self.depth_factor(y) * self.settings.strength.max(0.0)))
+    }
+}
+
+/// A flat ground plane at `height`, handy for testing.
+pub struct FlatGenerator {
+    pub height: f32,
+}
+
+impl DensityField for FlatGenerator {
+    fn density(&self, world_pos: Vector3<f32>) -> f32 {
+        world_pos.y - self.height
+    }
+
+    fn density_range(&self, y: f32) -> Option<(f32, f32)> {
+        Some((y - self.height, y - self.height))
+    }
+}
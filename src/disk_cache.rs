@@ -0,0 +1,227 @@
+use amethyst::core::math::Vector3;
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{marching_cubes::MeshData, seed::Seed};
+
+const HASH_FILE: &str = "config_hash";
+const MESH_EXTENSION: &str = "mesh";
+
+/// Least recently used cache of chunk meshes on disk, in `<root>/<seed>/<x>_<y>_<z>.mesh`,
+/// so chunks don't have to be regenerated every session. Holds at most `max_files` meshes.
+/// The cache is best effort: files that can't be read or written are treated as misses.
+pub struct DiskMeshCache {
+    dir: PathBuf,
+    max_files: usize,
+    tick: u64,
+    /// Last use of every cached chunk.
+    entries: HashMap<Vector3<i32>, u64>,
+}
+
+impl DiskMeshCache {
+    /// Opens the cache of a world, emptying it first if it was written with other
+    /// settings. `config_hash` identifies everything the meshes depend on.
+    pub fn open(
+        root: impl AsRef<Path>,
+        seed: &Seed,
+        config_hash: u128,
+        max_files: usize,
+    ) -> io::Result<Self> {
+        let seed_name: String = seed.bytes()[..16]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        let dir = root.as_ref().join(seed_name);
+        fs::create_dir_all(&dir)?;
+
+        let hash = format!("{:032x}", config_hash);
+        let hash_path = dir.join(HASH_FILE);
+        let stale = fs::read_to_string(&hash_path).map_or(true, |stored| stored.trim() != hash);
+
+        // Cached chunks in order of their last write, which becomes their last use
+        let mut found = vec![];
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().map_or(true, |ext| ext != MESH_EXTENSION) {
+                continue;
+            }
+            match (stale, parse_chunk(&path)) {
+                (false, Some(chunk)) => {
+                    let modified = fs::metadata(&path).and_then(|m| m.modified()).ok();
+                    found.push((modified, chunk));
+                }
+                _ => fs::remove_file(&path)?,
+            }
+        }
+        if stale {
+            fs::write(&hash_path, hash)?;
+        }
+        found.sort_by_key(|(modified, _)| *modified);
+
+        let mut cache = DiskMeshCache {
+            dir,
+            max_files,
+            tick: 0,
+            entries: HashMap::new(),
+        };
+        for (_, chunk) in found {
+            cache.tick += 1;
+            cache.entries.insert(chunk, cache.tick);
+        }
+        cache.evict();
+        return Ok(cache);
+    }
+
+    fn path(&self, chunk: Vector3<i32>) -> PathBuf {
+        let name = format!("{}_{}_{}.{}", chunk.x, chunk.y, chunk.z, MESH_EXTENSION);
+        return self.dir.join(name);
+    }
+
+    pub fn get(&mut self, chunk: Vector3<i32>) -> Option<MeshData> {
+        if !self.entries.contains_key(&chunk) {
+            return None;
+        }
+        let mesh_data = fs::read(self.path(chunk))
+            .ok()
+            .and_then(|bytes| bincode::deserialize(&bytes).ok());
+        match mesh_data {
+            Some(mesh_data) => {
+                self.tick += 1;
+                self.entries.insert(chunk, self.tick);
+                Some(mesh_data)
+            }
+            None => {
+                self.invalidate(chunk);
+                None
+            }
+        }
+    }
+
+    pub fn insert(&mut self, chunk: Vector3<i32>, mesh_data: &MeshData) {
+        if self.max_files == 0 {
+            return;
+        }
+        let bytes = match bincode::serialize(mesh_data) {
+            Ok(bytes) => bytes,
+            Err(_) => return,
+        };
+        // Written beside the final name and renamed, so a crash can't leave half a mesh
+        let path = self.path(chunk);
+        let tmp = path.with_extension("tmp");
+        if fs::write(&tmp, bytes).and_then(|_| fs::rename(&tmp, &path)).is_err() {
+            let _ = fs::remove_file(&tmp);
+            return;
+        }
+        self.tick += 1;
+        self.entries.insert(chunk, self.tick);
+        self.evict();
+    }
+
+    /// Drops the least recently used meshes until at most `max_files` are left.
+    fn evict(&mut self) {
+        while self.entries.len() > self.max_files {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, tick)| **tick)
+                .map(|(chunk, _)| *chunk);
+            match oldest {
+                Some(oldest) => self.invalidate(oldest),
+                None => break,
+            }
+        }
+    }
+
+    pub fn invalidate(&mut self, chunk: Vector3<i32>) {
+        if self.entries.remove(&chunk).is_some() {
+            let _ = fs::remove_file(self.path(chunk));
+        }
+    }
+
+    pub fn clear(&mut self) {
+        let chunks: Vec<Vector3<i32>> = self.entries.keys().copied().collect();
+        for chunk in chunks {
+            self.invalidate(chunk);
+        }
+    }
+
+    /// Empties the cache and marks it as written with other settings.
+    pub fn rehash(&mut self, config_hash: u128) -> io::Result<()> {
+        self.clear();
+        return fs::write(self.dir.join(HASH_FILE), format!("{:032x}", config_hash));
+    }
+
+    pub fn contains(&self, chunk: Vector3<i32>) -> bool {
+        self.entries.contains_key(&chunk)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Chunk coordinate of a cached mesh from its `<x>_<y>_<z>.mesh` file name.
+fn parse_chunk(path: &Path) -> Option<Vector3<i32>> {
+    let stem = path.file_stem()?.to_str()?;
+    let mut parts = stem.split('_').map(|part| part.parse::<i32>().ok());
+    let chunk = Vector3::new(parts.next()??, parts.next()??, parts.next()??);
+    if parts.next().is_some() {
+        return None;
+    }
+    return Some(chunk);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh cache root per test, so tests running at once don't share files.
+    fn temp_root(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("kyro-disk-cache-{}", name));
+        let _ = fs::remove_dir_all(&root);
+        root
+    }
+
+    #[test]
+    fn other_settings_empty_the_cache() {
+        let root = temp_root("rehash");
+        let seed = Seed::from(1u64);
+        let chunk = Vector3::new(1, -2, 3);
+        let mut cache = DiskMeshCache::open(&root, &seed, 1, 8).unwrap();
+        cache.insert(chunk, &MeshData::default());
+        let path = cache.path(chunk);
+        assert!(path.is_file());
+
+        // The same settings find the mesh again, others wipe it
+        let mut cache = DiskMeshCache::open(&root, &seed, 1, 8).unwrap();
+        assert!(cache.get(chunk).is_some());
+        let cache = DiskMeshCache::open(&root, &seed, 2, 8).unwrap();
+        assert!(cache.is_empty());
+        assert!(!path.exists());
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn least_recently_used_meshes_are_evicted() {
+        let root = temp_root("lru");
+        let mut cache = DiskMeshCache::open(&root, &Seed::from(2u64), 1, 2).unwrap();
+        let chunks = [Vector3::new(0, 0, 0), Vector3::new(1, 0, 0), Vector3::new(2, 0, 0)];
+        cache.insert(chunks[0], &MeshData::default());
+        cache.insert(chunks[1], &MeshData::default());
+        // Reading the first mesh makes the second the least recently used
+        assert!(cache.get(chunks[0]).is_some());
+        cache.insert(chunks[2], &MeshData::default());
+        assert_eq!(cache.len(), 2);
+        assert!(cache.contains(chunks[0]) && cache.contains(chunks[2]));
+        assert!(!cache.contains(chunks[1]));
+        assert!(!cache.path(chunks[1]).exists());
+        let _ = fs::remove_dir_all(&root);
+    }
+}
@@ -0,0 +1,88 @@
+use amethyst::core::math::Vector3;
+use rand::{prelude::StdRng, Rng};
+
+use crate::seed::Seed;
+
+/// Kinds of props scattered over the terrain surface.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FeatureKind {
+    Tree,
+    Bush,
+    Rock,
+}
+
+/// A prop placed on the terrain surface by `Terrain::get_chunk_features`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Feature {
+    pub kind: FeatureKind,
+    /// World position of the base of the prop, on the surface.
+    pub position: Vector3<f32>,
+    /// Rotation around the vertical axis, in radians.
+    pub rotation: f32,
+    pub scale: f32,
+}
+
+/// How props are scattered over the terrain surface.
+#[derive(Debug, Clone)]
+pub struct FeatureSettings {
+    /// Candidate points tried per chunk, most are rejected by the slope and sea level.
+    pub attempts: usize,
+    /// Steepest slope, in degrees from flat, a prop is placed on.
+    pub max_slope: f32,
+    /// Kinds to pick from with their relative weights.
+    pub kinds: Vec<(FeatureKind, f32)>,
+    /// Smallest and largest random scale.
+    pub scale_range: (f32, f32),
+}
+
+impl FeatureSettings {
+    /// Picks a kind by weight from `roll` in [0, 1).
+    pub(crate) fn pick_kind(&self, roll: f32) -> Option<FeatureKind> {
+        let total: f32 = self.kinds.iter().map(|(_, weight)| weight.max(0.0)).sum();
+        if total <= 0.0 {
+            return None;
+        }
+        let mut left = roll * total;
+        for &(kind, weight) in &self.kinds {
+            left -= weight.max(0.0);
+            if left < 0.0 {
+                return Some(kind);
+            }
+        }
+        return self.kinds.last().map(|(kind, _)| *kind);
+    }
+}
+
+impl Default for FeatureSettings {
+    fn default() -> Self {
+        FeatureSettings {
+            attempts: 8,
+            max_slope: 30.0,
+            kinds: vec![
+                (FeatureKind::Tree, 0.5),
+                (FeatureKind::Bush, 0.3),
+                (FeatureKind::Rock, 0.2),
+            ],
+            scale_range: (0.8, 1.25),
+        }
+    }
+}
+
+/// Random number generator for the features of a chunk. Depends only on the world seed
+/// and the chunk coordinate, so a chunk gets the same features every time it loads.
+pub(crate) fn chunk_rng(seed: &Seed, chunk: Vector3<i32>) -> StdRng {
+    let key = (u128::from(chunk.x as u32) << 64)
+        | (u128::from(chunk.y as u32) << 32)
+        | u128::from(chunk.z as u32);
+    let chunk_seed = Seed::from(key).bytes();
+    let mut bytes = seed.bytes();
+    for (byte, chunk_byte) in bytes.iter_mut().zip(chunk_seed.iter()) {
+        *byte ^= chunk_byte;
+    }
+    return Seed::new(bytes).rng();
+}
+
+/// Draws a random value in `[low, high)`, or `low` for an empty range.
+pub(crate) fn gen_range(rng: &mut StdRng, low: f32, high: f32) -> f32 {
+    return low + rng.gen::<f32>() * (high - low).max(0.0);
+}
@@ -0,0 +1,18 @@
+pub mod character_systems;
+pub mod chunk_generation;
+pub mod chunk_systems;
+pub mod components;
+pub mod controls;
+pub mod density;
+pub mod disk_cache;
+pub mod features;
+pub mod marching_cubes;
+pub mod matrix_3d;
+pub mod mesh_cache;
+pub mod noise_layers;
+pub mod save_systems;
+pub mod seed;
+pub mod terrain;
+pub mod terrain_config;
+pub mod visual_utils;
+pub mod world_save;
@@ -1,22 +1,17 @@
 use amethyst::{
-    assets::AssetLoaderSystemData,
     core::{
-        math::{Point3, Vector3},
+        math::Vector3,
         transform::{Transform, TransformBundle},
         Parent,
     },
     input::{InputBundle, StringBindings},
     prelude::*,
     renderer::{
-        self,
         camera::Camera,
         light,
         palette::{LinSrgba, Srgb},
         plugins::{RenderShaded3D, RenderToWindow},
-        rendy::mesh::MeshBuilder,
         types,
-        types::{Mesh, MeshData},
-        visibility::BoundingSphere,
         RenderingBundle,
     },
     utils::application_root_dir,
@@ -27,16 +22,23 @@ use rand::prelude::*;
 
 use amethyst_nphysics::NPhysicsBackend;
 use amethyst_physics::{prelude::*, PhysicsBundle};
-use renderer::rendy::mesh::Indices;
 
-mod character_systems;
-mod components;
-mod marching_cubes;
-mod matrix_3d;
-mod terrain;
-mod visual_utils;
+use kyro::{
+    character_systems,
+    chunk_generation::ChunkGenerator,
+    chunk_systems::{self, ChunkManager},
+    components, controls,
+    save_systems::{self, Autosave},
+    seed,
+    terrain::{SharedTerrain, Terrain},
+    terrain_config::TerrainConfig,
+    visual_utils,
+    world_save::WorldSave,
+};
+use std::sync::{Arc, RwLock};
 
-use terrain::Terrain;
+/// Most chunk meshes kept in the disk cache.
+const MESH_CACHE_FILES: usize = 4096;
 
 #[derive(Default)]
 struct Example;
@@ -57,28 +59,43 @@ impl SimpleState for Example {
             0.2,
         );
 
-        // Create terrain
-
-        let mut terrain = Terrain::new(
-            random(),
-            15,
-            1.0,
-            vec![0.3, 0.65, 0.05],
-            vec![0.05, 0.1, 10.0],
-        );
-        data.world.register::<components::Chunk>();
-        let size = 5;
-        for z in -size..(size + 1) {
-            for y in -size..(size + 1) {
-                for x in -size..(size + 1) {
-                    create_chunk(data.world, &mut terrain, x, y, z);
-                }
-            }
+        // Load the saved world or start a new one, the chunks around the character are
+        // loaded by the chunk manager
+        let app_root = application_root_dir().unwrap();
+        let save = WorldSave::new(app_root.join("saves").join("world"));
+        let mut terrain = load_world(&save);
+        let cache_dir = app_root.join("cache");
+        if let Err(e) = terrain.enable_disk_cache(cache_dir, MESH_CACHE_FILES) {
+            log::error!("Couldn't open the chunk mesh cache: {}", e);
         }
+        data.world.register::<components::Chunk>();
+        let material = visual_utils::create_material(
+            data.world,
+            LinSrgba::new(0.7188, 0.1578, 0.0, 1.0),
+            0.0, // Metallic
+            1.0, // Roughness
+        );
+        let terrain: SharedTerrain = Arc::new(RwLock::new(terrain));
+        data.world.insert(ChunkGenerator::new(
+            terrain.clone(),
+            rayon::current_num_threads(),
+        ));
+        data.world.insert(terrain);
+        data.world.insert(ChunkManager::new(material));
+        data.world.insert(Autosave::new(save));
 
         // Create the character + camera.
         create_character_entity(data.world);
     }
+
+    fn on_stop(&mut self, data: StateData<'_, GameData<'_, '_>>) {
+        // Save any edits made since the last autosave
+        let terrain = data.world.read_resource::<SharedTerrain>().clone();
+        let result = data.world.write_resource::<Autosave>().finish(&terrain);
+        if let Err(e) = result {
+            log::error!("Couldn't save the world: {}", e);
+        }
+    }
 }
 
 fn main() -> Result<(), Error> {
@@ -88,18 +105,53 @@ fn main() -> Result<(), Error> {
 
     let assets_dir = app_root.join("assets");
     let display_config_path = app_root.join("config").join("display.ron");
+    let bindings = controls::load_bindings_or_default(
+        app_root.join("config").join("input_bindings.ron"),
+        assets_dir.join("input_bindings.ron"),
+    )?;
 
     let game_data = GameDataBuilder::default()
-        .with_bundle(
-            InputBundle::<StringBindings>::new()
-                .with_bindings_from_file(assets_dir.join("input_bindings.ron"))
-                .unwrap(),
-        )?
+        .with_bundle(InputBundle::<StringBindings>::new().with_bindings(bindings))?
         .with(
             character_systems::CameraMotionSystem::new(),
             "camera_motion_system",
             &["input_system"],
         )
+        .with(
+            character_systems::CameraCollisionSystem::default(),
+            "camera_collision_system",
+            &["camera_motion_system"],
+        )
+        .with(
+            character_systems::HeadBobSystem::default(),
+            "head_bob_system",
+            &["camera_collision_system"],
+        )
+        .with(
+            character_systems::CameraShakeSystem::new(),
+            "camera_shake_system",
+            &["head_bob_system"],
+        )
+        .with(
+            character_systems::TerrainInteractionSystem::new(),
+            "terrain_interaction_system",
+            &["input_system", "camera_collision_system"],
+        )
+        .with(
+            chunk_systems::ChunkRemeshSystem::default(),
+            "chunk_remesh_system",
+            &["terrain_interaction_system"],
+        )
+        .with(
+            chunk_systems::ChunkManagerSystem::default(),
+            "chunk_manager_system",
+            &["chunk_remesh_system"],
+        )
+        .with(
+            save_systems::AutosaveSystem::default(),
+            "autosave_system",
+            &["terrain_interaction_system"],
+        )
         .with_bundle(TransformBundle::new())?
         .with_bundle(
             PhysicsBundle::<f32, NPhysicsBackend>::new()
@@ -125,6 +177,30 @@ fn main() -> Result<(), Error> {
     Ok(())
 }
 
+/// Loads the world in `save`, or starts a new one if there is none. A save that can't be
+/// loaded is moved aside, so starting over doesn't overwrite it.
+fn load_world(save: &WorldSave) -> Terrain {
+    if save.exists() {
+        match save.load() {
+            Ok(terrain) => return terrain,
+            Err(e) => {
+                log::error!("Couldn't load the world in {}: {}", save.dir().display(), e);
+                let broken = (0..)
+                    .map(|i| save.dir().with_extension(format!("broken{}", i)))
+                    .find(|dir| !dir.exists())
+                    .unwrap();
+                match std::fs::rename(save.dir(), &broken) {
+                    Ok(()) => log::error!("Moved it to {}", broken.display()),
+                    Err(e) => log::error!("Couldn't move it aside: {}", e),
+                }
+                log::error!("Starting a new world");
+            }
+        }
+    }
+    let config = TerrainConfig::new(seed::Seed::new(random()));
+    Terrain::from_config(config).expect("default terrain config")
+}
+
 fn add_light_entity(world: &mut World, color: Srgb, direction: Vector3<f32>, intensity: f32) {
     let light: light::Light = light::DirectionalLight {
         color,
@@ -136,100 +212,18 @@ fn add_light_entity(world: &mut World, color: Srgb, direction: Vector3<f32>, int
     world.create_entity().with(light).build();
 }
 
-fn create_chunk(
-    world: &mut World,
-    terrain: &mut Terrain,
-    chunk_x: i16,
-    chunk_y: i16,
-    chunk_z: i16,
-) {
-    let rb = {
-        let mut rb_desc = RigidBodyDesc::default();
-        rb_desc.mode = BodyMode::Static;
-
-        let physics_world = world.fetch::<PhysicsWorld<f32>>();
-        physics_world.rigid_body_server().create(&rb_desc)
-    };
-    let (indicies, posns, norms, coords) =
-        terrain.get_chunk(Vector3::new(chunk_x, chunk_y, chunk_z)).get_mesh_data();
-    if indicies.len() == 0 {
-        return;
-    }
-    let mut indicies_collision = Vec::new();
-    for i in 0..posns.len() / 3 {
-        indicies_collision.push(Point3::from_slice(&[i * 3, i * 3 + 1, i * 3 + 2]));
-    }
-    let mut points_collision = Vec::new();
-    for p in &posns {
-        points_collision.push(Point3::from_slice(&[p.0[0], p.0[1], p.0[2]]))
-    }
-    let mesh = world.exec(|loader: AssetLoaderSystemData<Mesh>| {
-        loader.load_from_data(
-            MeshData(
-                MeshBuilder::new()
-                    .with_vertices(posns)
-                    .with_vertices(norms)
-                    .with_vertices(coords)
-                    .with_indices(Indices::U16(indicies.into())),
-            ),
-            (),
-        )
-    });
-
-    let shape = {
-        let desc = ShapeDesc::TriMesh {
-            indices: indicies_collision,
-            points: points_collision,
-        };
-        let physics_world = world.fetch::<PhysicsWorld<f32>>();
-        physics_world.shape_server().create(&desc)
-    };
-
-    let mat = visual_utils::create_material(
-        world,
-        LinSrgba::new(0.7188, 0.1578, 0.0, 1.0),
-        0.0, // Metallic
-        1.0, // Roughness
-    );
-
-    let mut transform = Transform::default();
-    transform.set_translation_xyz(
-        chunk_x as f32 * terrain.chunk_size(),
-        chunk_y as f32 * terrain.chunk_size(),
-        chunk_z as f32 * terrain.chunk_size(),
-    );
-    world
-        .create_entity()
-        .with(mesh)
-        .with(mat)
-        .with(BoundingSphere::new(
-            Point3::new(
-                terrain.chunk_size() / 2.0,
-                terrain.chunk_size() / 2.0,
-                terrain.chunk_size() / 2.0,
-            ),
-            terrain.chunk_size() * 1.5,
-        ))
-        .with(transform)
-        .with(shape)
-        .with(rb)
-        .with(components::Chunk)
-        .build();
-}
-
 /// Creates three entities:
 /// 1. The character (With RigidBody).
 /// 2. The camera boom handle attached to the character.
 /// 3. The camera attached to the camera bool handle.
 fn create_character_entity(world: &mut World) {
     let character = {
+        let movement_config = components::MovementConfig::default();
         let shape = {
-            let desc = ShapeDesc::Capsule {
-                half_height: 0.75,
-                radius: 0.5,
-            };
             let physics_world = world.fetch::<PhysicsWorld<f32>>();
-            physics_world.shape_server().create(&desc)
+            physics_world
+                .shape_server()
+                .create(&movement_config.capsule(false))
         };
 
         let rb = {
@@ -253,7 +247,10 @@ fn create_character_entity(world: &mut World) {
             .with(transf)
             .with(shape)
             .with(rb)
-            .with(components::CharacterBody)
+            .with(components::CharacterBody::default())
+            .with(components::GroundContact::default())
+            .with(movement_config)
+            .with(components::Stamina::default())
             .build()
     };
 
@@ -264,7 +261,8 @@ fn create_character_entity(world: &mut World) {
         world
             .create_entity()
             .with(transf)
-            .with(components::CameraBoomHandle)
+            .with(components::CameraBoomHandle::default())
+            .with(components::CameraOrientation::default())
             .with(Parent { entity: character })
             .build()
     };
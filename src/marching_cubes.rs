@@ -1,16 +1,40 @@
 use crate::matrix_3d::Matrix3D;
-use amethyst::renderer::rendy::mesh::{Normal, Position, TexCoord};
+use amethyst::{
+    assets::{AssetStorage, Handle, Loader},
+    renderer::{
+        rendy::{
+            hal::pso::Primitive,
+            mesh::{Color, Indices, MeshBuilder, Normal, Position, Tangent, TexCoord},
+        },
+        types::{self, Mesh},
+    },
+};
 use lazy_static::lazy_static;
 use ron::from_str;
-use serde::Deserialize;
-use std::fs;
+use serde::{Deserialize, Serialize};
+use amethyst_physics::prelude::ShapeDesc;
+use std::{collections::HashMap, env, error::Error, fmt, fs, io};
 use amethyst::core::math::{
-    Vector2, Vector3, //Matrix3
+    Point3, Vector2, Vector3, //Matrix3
 };
 
+/// Environment variable pointing at a custom triangulation table, used instead of the
+/// embedded one when set. A table that can't be read or is incomplete is ignored with a
+/// warning.
+pub const TRIANGULATION_PATH_VAR: &str = "KYRO_TRIANGULATION";
+
+const DEFAULT_TRIANGULATION: &str = include_str!("../assets/triangulation.ron");
+
 lazy_static! {
     static ref TRIANGULATION: Triangulation = {
-        return from_str(&fs::read_to_string("assets/triangulation.ron").unwrap()).unwrap();
+        let embedded = || parse_triangulation(DEFAULT_TRIANGULATION).unwrap();
+        return match env::var(TRIANGULATION_PATH_VAR) {
+            Ok(path) => load_triangulation(&path).unwrap_or_else(|e| {
+                log::warn!("Couldn't load the triangulation in {}, using the default: {}", path, e);
+                embedded()
+            }),
+            Err(_) => embedded(),
+        };
     };
 
     static ref TRI_TABLE: Vec<Vec<u8>> = {
@@ -33,92 +57,522 @@ struct Triangulation {
     cube_edges: Vec<(usize, usize)>
 }
 
-const CUTOFF: f32 = 0.0;
+impl Triangulation {
+    /// Rejects a table without a case for each of the 256 corner combinations or
+    /// without the 8 corners and 12 edges of a cube.
+    fn check(self) -> Result<Self, TriangulationError> {
+        let counts = [
+            ("triangulation_table", 256, self.triangulation_table.len()),
+            ("cube_points", 8, self.cube_points.len()),
+            ("cube_edges", 12, self.cube_edges.len()),
+        ];
+        for &(field, expected, found) in counts.iter() {
+            if found != expected {
+                return Err(TriangulationError::Entries { field, expected, found });
+            }
+        }
+        return Ok(self);
+    }
+}
 
-fn get_cube_tris(matrix: &Matrix3D, vector: Vector3<usize>) -> Vec<Vector3<f32>> {
-    let mut tris = vec![];
-    let mut id = 0;
-    let mut vals = [0.0; 8];
-    for i in 0..8 {
-        let points = &CUBE_POINTS[i];
-        let val = matrix.get(Vector3::new(
-            vector.x + points.0,
-            vector.y + points.1,
-            vector.z + points.2)
-        );
-        vals[i] = val;
-        if val < CUTOFF {
-            id += 2usize.pow(i as u32);
+/// Reasons a custom triangulation table can't be used.
+#[derive(Debug)]
+pub enum TriangulationError {
+    Io(io::Error),
+    Parse(ron::Error),
+    /// A field of the table has the wrong number of entries.
+    Entries { field: &'static str, expected: usize, found: usize },
+}
+
+impl fmt::Display for TriangulationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TriangulationError::Io(e) => write!(f, "couldn't read triangulation: {}", e),
+            TriangulationError::Parse(e) => write!(f, "couldn't parse triangulation: {}", e),
+            TriangulationError::Entries { field, expected, found } => write!(
+                f,
+                "{} needs {} entries, found {}",
+                field, expected, found
+            ),
         }
     }
-    for i in 0..TRI_TABLE[id].len() / 3 {
-        let edges = [
-            TRI_TABLE[id][i * 3],
-            TRI_TABLE[id][i * 3 + 1],
-            TRI_TABLE[id][i * 3 + 2],
-        ];
-        for j in 0..3 {
-            let edge = CUBE_EDGES[edges[j] as usize];
-            let start = CUBE_POINTS[edge.0];
-            let end = CUBE_POINTS[edge.1];
-            let start_density = vals[edge.0];
-            let end_density = vals[edge.1];
-            let start_weight;
-            let end_weight;
-            if end_density < start_density {
-                start_weight = (CUTOFF - end_density) / (start_density - end_density);
-                end_weight = 1.0 - start_weight;
-            } else {
-                end_weight = (CUTOFF - start_density) / (end_density - start_density);
-                start_weight = 1.0 - end_weight;
+}
+
+impl Error for TriangulationError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            TriangulationError::Io(e) => Some(e),
+            TriangulationError::Parse(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for TriangulationError {
+    fn from(e: io::Error) -> Self {
+        TriangulationError::Io(e)
+    }
+}
+
+impl From<ron::Error> for TriangulationError {
+    fn from(e: ron::Error) -> Self {
+        TriangulationError::Parse(e)
+    }
+}
+
+fn parse_triangulation(source: &str) -> Result<Triangulation, TriangulationError> {
+    let table: Triangulation = from_str(source)?;
+    return table.check();
+}
+
+fn load_triangulation(path: &str) -> Result<Triangulation, TriangulationError> {
+    let source = fs::read_to_string(path)?;
+    return parse_triangulation(&source);
+}
+
+/// Density at which the iso-surface is extracted; values below it are solid.
+pub const DEFAULT_CUTOFF: f32 = 0.0;
+
+/// Triangles whose cross product, in grid units, is shorter than this are treated as
+/// degenerate.
+const DEGENERATE_EPSILON: f32 = 1e-6;
+
+/// How vertex normals are generated for the mesh.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NormalMode {
+    /// One normal per triangle, for a faceted low-poly look.
+    Flat,
+    /// Normals taken from the gradient of the density field, for smooth shading.
+    Smooth,
+}
+
+impl Default for NormalMode {
+    fn default() -> Self {
+        NormalMode::Flat
+    }
+}
+
+/// A corner of a unit cube, each coordinate 0 or 1.
+pub type Corner = (usize, usize, usize);
+
+/// Splits the surface through a unit cube of the density grid into triangles.
+pub trait Polygonizer: Sync {
+    /// Appends three corner pairs for every triangle through the cube, one per vertex,
+    /// naming the two corners whose edge the vertex lies on. `solid` tells whether the
+    /// density at a corner is below the cutoff. Triangles must face away from the solid
+    /// side, with `(v1 - v0) x (v2 - v1)` as the front.
+    fn polygonize(&self, solid: &dyn Fn(Corner) -> bool, out: &mut Vec<(Corner, Corner)>);
+}
+
+/// Classic marching cubes on the triangulation table. Few triangles, but the table
+/// doesn't resolve ambiguous faces, which can leave small holes or mismatched surfaces
+/// between cubes in tricky spots.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MarchingCubes;
+
+impl Polygonizer for MarchingCubes {
+    fn polygonize(&self, solid: &dyn Fn(Corner) -> bool, out: &mut Vec<(Corner, Corner)>) {
+        let mut id = 0;
+        for (i, &corner) in CUBE_POINTS.iter().enumerate() {
+            if solid(corner) {
+                id += 1 << i;
+            }
+        }
+        for &edge in &TRI_TABLE[id] {
+            let (start, end) = CUBE_EDGES[edge as usize];
+            out.push((CUBE_POINTS[start], CUBE_POINTS[end]));
+        }
+    }
+}
+
+/// The six tetrahedra a cube is split into by `MarchingTetrahedra`, all around the
+/// diagonal from (0, 0, 0) to (1, 1, 1). Every cube is split the same way, so the face
+/// diagonals of neighbouring cubes line up.
+const CUBE_TETRAHEDRA: [[Corner; 4]; 6] = [
+    [(0, 0, 0), (1, 0, 0), (1, 1, 0), (1, 1, 1)],
+    [(0, 0, 0), (1, 1, 0), (0, 1, 0), (1, 1, 1)],
+    [(0, 0, 0), (0, 1, 0), (0, 1, 1), (1, 1, 1)],
+    [(0, 0, 0), (0, 1, 1), (0, 0, 1), (1, 1, 1)],
+    [(0, 0, 0), (0, 0, 1), (1, 0, 1), (1, 1, 1)],
+    [(0, 0, 0), (1, 0, 1), (1, 0, 0), (1, 1, 1)],
+];
+
+/// Marching tetrahedra: splits every cube into six tetrahedra, which have no ambiguous
+/// cases, so the surface is always closed and consistent. Costs about two to three
+/// times the triangles of `MarchingCubes`, with thinner triangles.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MarchingTetrahedra;
+
+impl Polygonizer for MarchingTetrahedra {
+    fn polygonize(&self, solid: &dyn Fn(Corner) -> bool, out: &mut Vec<(Corner, Corner)>) {
+        for tetra in &CUBE_TETRAHEDRA {
+            // Solid corners first, then the open ones
+            let mut sorted = [(0, 0, 0); 4];
+            let mut solid_count = 0;
+            let mut open_index = 4;
+            for &corner in tetra {
+                if solid(corner) {
+                    sorted[solid_count] = corner;
+                    solid_count += 1;
+                } else {
+                    open_index -= 1;
+                    sorted[open_index] = corner;
+                }
+            }
+            let [a, b, c, d] = sorted;
+            let tris = match solid_count {
+                1 => [[(a, b), (a, c), (a, d)], [(a, a); 3]],
+                2 => [[(a, c), (a, d), (b, d)], [(a, c), (b, d), (b, c)]],
+                3 => [[(a, d), (b, d), (c, d)], [(a, a); 3]],
+                _ => continue,
+            };
+            // Face the triangles from the solid corners towards the open ones
+            let outward = match solid_count {
+                1 => corner_position(b) + corner_position(c) + corner_position(d)
+                    - corner_position(a) * 3.0,
+                2 => corner_position(c) + corner_position(d)
+                    - corner_position(a)
+                    - corner_position(b),
+                _ => corner_position(d) * 3.0
+                    - corner_position(a)
+                    - corner_position(b)
+                    - corner_position(c),
+            };
+            let count = if solid_count == 2 { 2 } else { 1 };
+            for tri in &tris[..count] {
+                let mut tri = *tri;
+                let pts = [
+                    edge_midpoint(tri[0]),
+                    edge_midpoint(tri[1]),
+                    edge_midpoint(tri[2]),
+                ];
+                let face = (pts[1] - pts[0]).cross(&(pts[2] - pts[1]));
+                if face.dot(&outward) < 0.0 {
+                    tri.swap(1, 2);
+                }
+                out.extend_from_slice(&tri);
+            }
+        }
+    }
+}
+
+fn corner_position(corner: Corner) -> Vector3<f32> {
+    return Vector3::new(corner.0 as f32, corner.1 as f32, corner.2 as f32);
+}
+
+fn edge_midpoint((start, end): (Corner, Corner)) -> Vector3<f32> {
+    return (corner_position(start) + corner_position(end)) * 0.5;
+}
+
+/// Which `Polygonizer` meshes the density grid.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polygonization {
+    MarchingCubes,
+    MarchingTetrahedra,
+}
+
+impl Default for Polygonization {
+    fn default() -> Self {
+        Polygonization::MarchingCubes
+    }
+}
+
+impl Polygonization {
+    pub fn polygonizer(&self) -> &'static dyn Polygonizer {
+        match self {
+            Polygonization::MarchingCubes => &MarchingCubes,
+            Polygonization::MarchingTetrahedra => &MarchingTetrahedra,
+        }
+    }
+}
+
+/// How texture coordinates are generated for the mesh.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UvMode {
+    /// Every vertex gets `[0.0, 0.0]`.
+    None,
+    /// Project each vertex onto the axis plane its normal is closest to. `scale` is the
+    /// number of texture repeats per world unit.
+    Triplanar { scale: f32 },
+    /// Project every vertex onto the horizontal XZ plane.
+    WorldXZ { scale: f32 },
+}
+
+impl Default for UvMode {
+    fn default() -> Self {
+        UvMode::None
+    }
+}
+
+impl UvMode {
+    fn coord(&self, pos: &Vector3<f32>, normal: &Vector3<f32>) -> [f32; 2] {
+        match *self {
+            UvMode::None => [0.0, 0.0],
+            UvMode::WorldXZ { scale } => [pos.x * scale, pos.z * scale],
+            UvMode::Triplanar { scale } => {
+                let n = normal.abs();
+                if n.x >= n.y && n.x >= n.z {
+                    [pos.z * scale, pos.y * scale]
+                } else if n.y >= n.z {
+                    [pos.x * scale, pos.z * scale]
+                } else {
+                    [pos.x * scale, pos.y * scale]
+                }
+            }
+        }
+    }
+}
+
+/// Settings for `get_mesh_data_with`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MeshOptions {
+    pub cutoff: f32,
+    pub normal_mode: NormalMode,
+    pub uv_mode: UvMode,
+    pub polygonization: Polygonization,
+    /// Extra points around the matrix that are only used for gradients, see
+    /// `Terrain::get_matrix_with_margin`. Only the cubes inside the margin are meshed.
+    pub margin: usize,
+    /// World position of the first meshed point. Added to the vertex positions when
+    /// generating texture coordinates, so textures line up across chunk borders.
+    pub uv_origin: Vector3<f32>,
+}
+
+impl Default for MeshOptions {
+    fn default() -> Self {
+        MeshOptions {
+            cutoff: DEFAULT_CUTOFF,
+            normal_mode: NormalMode::default(),
+            uv_mode: UvMode::default(),
+            polygonization: Polygonization::default(),
+            margin: 0,
+            uv_origin: Vector3::zeros(),
+        }
+    }
+}
+
+/// Grid points on each side of a vertex checked by `MeshData::bake_ambient_occlusion`.
+pub const AO_RADIUS: usize = 2;
+
+/// Number of materials a vertex can blend, one per channel of its material weights.
+pub const MATERIAL_CHANNELS: usize = 4;
+
+/// Weights selecting a single material, out of range indices use the last channel.
+fn material_weights(material: f32) -> [f32; MATERIAL_CHANNELS] {
+    let mut weights = [0.0; MATERIAL_CHANNELS];
+    weights[(material.max(0.0) as usize).min(MATERIAL_CHANNELS - 1)] = 1.0;
+    return weights;
+}
+
+/// Central-difference gradient of the density field at a grid point, falling back to
+/// one-sided differences on the boundary of the matrix.
+fn gradient(matrix: &Matrix3D, point: Vector3<usize>) -> Vector3<f32> {
+    let size = Vector3::new(matrix.x(), matrix.y(), matrix.z());
+    let mut grad = Vector3::zeros();
+    for axis in 0..3 {
+        let mut lo = point;
+        let mut hi = point;
+        if point[axis] > 0 {
+            lo[axis] -= 1;
+        }
+        if point[axis] + 1 < size[axis] {
+            hi[axis] += 1;
+        }
+        if hi[axis] != lo[axis] {
+            grad[axis] =
+                (matrix.get(hi) - matrix.get(lo)) / (hi[axis] - lo[axis]) as f32;
+        }
+    }
+    return grad;
+}
+
+/// Per-cube output of `get_cube_tris`, kept between cubes so meshing a chunk doesn't
+/// allocate for every cube.
+#[derive(Default)]
+struct CubeScratch {
+    edges: Vec<(Corner, Corner)>,
+    tris: Vec<Vector3<f32>>,
+    grads: Vec<Vector3<f32>>,
+    mats: Vec<[f32; MATERIAL_CHANNELS]>,
+    /// Whether each triangle has coincident vertices, judged in grid units.
+    degenerate: Vec<bool>,
+}
+
+/// Fills `scratch` with the vertices of the triangles in a cube, with the density gradient
+/// at each vertex for smooth normals and the blended material weights when a material
+/// matrix is given.
+fn get_cube_tris(
+    matrix: &Matrix3D,
+    materials: Option<&Matrix3D>,
+    vector: Vector3<usize>,
+    options: &MeshOptions,
+    scratch: &mut CubeScratch,
+) {
+    scratch.edges.clear();
+    scratch.tris.clear();
+    scratch.grads.clear();
+    scratch.mats.clear();
+    let CubeScratch { edges, tris, grads, mats } = scratch;
+    let cutoff = options.cutoff;
+    let point = |p: Corner| Vector3::new(vector.x + p.0, vector.y + p.1, vector.z + p.2);
+    options
+        .polygonization
+        .polygonizer()
+        .polygonize(&|corner| matrix.get(point(corner)) < cutoff, edges);
+    for &(start, end) in edges.iter() {
+        let start_density = matrix.get(point(start));
+        let end_density = matrix.get(point(end));
+        let start_weight;
+        let end_weight;
+        if (start_density - end_density).abs() < f32::EPSILON {
+            // Equal densities give no crossing point, use the edge midpoint.
+            start_weight = 0.5;
+            end_weight = 0.5;
+        } else if end_density < start_density {
+            // Clamped, rounding can put the crossing just past the end of the edge
+            start_weight = ((cutoff - end_density) / (start_density - end_density))
+                .max(0.0)
+                .min(1.0);
+            end_weight = 1.0 - start_weight;
+        } else {
+            end_weight = ((cutoff - start_density) / (end_density - start_density))
+                .max(0.0)
+                .min(1.0);
+            start_weight = 1.0 - end_weight;
+        }
+        let x = start.0 as f32 * start_weight + end.0 as f32 * end_weight;
+        let y = start.1 as f32 * start_weight + end.1 as f32 * end_weight;
+        let z = start.2 as f32 * start_weight + end.2 as f32 * end_weight;
+        tris.push(Vector3::new(x, y, z));
+
+        if options.normal_mode == NormalMode::Smooth {
+            let start_grad = gradient(matrix, point(start));
+            let end_grad = gradient(matrix, point(end));
+            grads.push(start_grad * start_weight + end_grad * end_weight);
+        }
+
+        if let Some(materials) = materials {
+            let start_mat = material_weights(materials.get(point(start)));
+            let end_mat = material_weights(materials.get(point(end)));
+            let mut weights = [0.0; MATERIAL_CHANNELS];
+            for c in 0..MATERIAL_CHANNELS {
+                weights[c] = start_mat[c] * start_weight + end_mat[c] * end_weight;
             }
-            let x = start.0 as f32 * start_weight + end.0 as f32 * end_weight;
-            let y = start.1 as f32 * start_weight + end.1 as f32 * end_weight;
-            let z = start.2 as f32 * start_weight + end.2 as f32 * end_weight;
-            tris.push(Vector3::new(x, y, z));
+            mats.push(weights);
         }
     }
-    return tris;
 }
 
 fn correct(
-    pts: Vec<Vector3<f32>>,
-    scale: f32,
+    pts: &mut [Vector3<f32>],
+    scale: Vector3<f32>,
     displace: Vector3<usize>,
-) -> Vec<Vector3<f32>> {
-    let mut new = vec![];
+) {
     for pt in pts {
-        new.push(Vector3::new(
-            pt.x * scale + displace.x as f32 * scale,
-            pt.y * scale + displace.y as f32 * scale,
-            pt.z * scale + displace.z as f32 * scale,
-        ))
+        *pt = Vector3::new(
+            pt.x * scale.x + displace.x as f32 * scale.x,
+            pt.y * scale.y + displace.y as f32 * scale.y,
+            pt.z * scale.z + displace.z as f32 * scale.z,
+        );
     }
-    return new;
 }
 
-pub fn get_mesh_data(matrix: &Matrix3D, scale: f32) -> MeshData {
+/// Meshes a density matrix whose points are `scale` apart along each axis.
+pub fn get_mesh_data(matrix: &Matrix3D, scale: Vector3<f32>, cutoff: f32) -> MeshData {
+    return get_mesh_data_with(
+        matrix,
+        scale,
+        &MeshOptions {
+            cutoff,
+            ..MeshOptions::default()
+        },
+    );
+}
+
+pub fn get_mesh_data_with(
+    matrix: &Matrix3D,
+    scale: Vector3<f32>,
+    options: &MeshOptions,
+) -> MeshData {
+    return mesh(matrix, None, scale, options);
+}
+
+/// Like `get_mesh_data_with`, also giving every vertex material weights, see
+/// `MeshData::material_weights`. `materials` holds a material index in
+/// `0..MATERIAL_CHANNELS` for each point of `matrix`.
+pub fn get_mesh_data_with_materials(
+    matrix: &Matrix3D,
+    materials: &Matrix3D,
+    scale: Vector3<f32>,
+    options: &MeshOptions,
+) -> MeshData {
+    return mesh(matrix, Some(materials), scale, options);
+}
+
+fn mesh(
+    matrix: &Matrix3D,
+    materials: Option<&Matrix3D>,
+    scale: Vector3<f32>,
+    options: &MeshOptions,
+) -> MeshData {
+    // Fully solid or fully empty matrices have no surface to extract
+    let (min, max) = matrix.min_max();
+    if max < options.cutoff || min >= options.cutoff {
+        return MeshData::default();
+    }
+
     let mut posns = vec![];
     let mut norms = vec![];
     let mut coords = vec![];
-    for z in 0..(matrix.z() - 1) {
-        for y in 0..(matrix.y() - 1) {
-            for x in 0..(matrix.x() - 1) {
+    let mut mats = vec![];
+    let mut bounds = None;
+    let mut scratch = CubeScratch::default();
+    let margin = options.margin;
+    for z in margin..(matrix.z() - 1 - margin) {
+        for y in margin..(matrix.y() - 1 - margin) {
+            for x in margin..(matrix.x() - 1 - margin) {
                 let vec3 = Vector3::new(x, y, z);
-                let pts = correct(get_cube_tris(matrix, vec3), scale, vec3);
-
-                for pt in &pts {
-                    posns.push(Position {
-                        0: [pt.x, pt.y, pt.z],
-                    });
+                get_cube_tris(matrix, materials, vec3, options, &mut scratch);
+                // Judged before scaling, so a small scale doesn't drop real triangles
+                scratch.degenerate.clear();
+                for tri in scratch.tris.chunks(3) {
+                    let area = (tri[1] - tri[0]).cross(&(tri[2] - tri[1])).norm();
+                    scratch.degenerate.push(area.is_nan() || area <= DEGENERATE_EPSILON);
                 }
+                correct(&mut scratch.tris, scale, vec3 - Vector3::repeat(margin));
+                let pts = &scratch.tris;
+
                 for i in 0..pts.len() / 3 {
-                    let normal: Vector3<f32> =  (&pts[i * 3 + 1] - &pts[i * 3]).cross(&(&pts[i * 3 + 2] - &pts[i * 3 + 1]));
-                    for _ in 0..3 {
+                    if scratch.degenerate[i] {
+                        // Coincident vertices, skip the triangle rather than emit a NaN normal.
+                        continue;
+                    }
+                    let tri = [&pts[i * 3], &pts[i * 3 + 1], &pts[i * 3 + 2]];
+                    let face: Vector3<f32> = (tri[1] - tri[0]).cross(&(tri[2] - tri[1]));
+                    let face = face / face.norm();
+                    for j in 0..3 {
+                        let normal = match options.normal_mode {
+                            NormalMode::Flat => face,
+                            NormalMode::Smooth => {
+                                // The gradient is per grid step, stretch it into world space
+                                let grad = scratch.grads[i * 3 + j].component_div(&scale);
+                                let len = grad.norm();
+                                if len > 0.0 { grad / len } else { face }
+                            }
+                        };
+                        posns.push(Position {
+                            0: [tri[j].x, tri[j].y, tri[j].z],
+                        });
+                        extend_bounds(&mut bounds, *tri[j]);
                         norms.push(Normal {
                             0: [normal.x, normal.y, normal.z],
                         });
-                        coords.push(TexCoord { 0: [0.0, 0.0] });
+                        coords.push(TexCoord {
+                            0: options.uv_mode.coord(&(tri[j] + options.uv_origin), &normal),
+                        });
+                        if materials.is_some() {
+                            mats.push(scratch.mats[i * 3 + j]);
+                        }
                     }
                 }
             }
@@ -128,8 +582,20 @@ pub fn get_mesh_data(matrix: &Matrix3D, scale: f32) -> MeshData {
         posns,
         norms,
         coords,
+        mats,
+        ao: vec![],
+        colors: vec![],
+        bounds,
     };
 }
+
+/// Grows a bounding box, `None` while empty, to contain `pos`.
+fn extend_bounds(bounds: &mut Option<(Vector3<f32>, Vector3<f32>)>, pos: Vector3<f32>) {
+    *bounds = Some(match *bounds {
+        Some((min, max)) => (min.inf(&pos), max.sup(&pos)),
+        None => (pos, pos),
+    });
+}
 /*
 fn sub(a: (f32, f32, f32), b: (f32, f32, f32)) -> (f32, f32, f32) {
     return (a.0 - b.0, a.1 - b.1, a.2 - b.2);
@@ -143,19 +609,785 @@ fn cross(a: (f32, f32, f32), b: (f32, f32, f32)) -> (f32, f32, f32) {
     );
 }*/
 
+/// `MeshData` in plain floats, which serde can handle unlike the rendy vertex types.
+#[derive(Serialize, Deserialize)]
+struct RawMeshData {
+    posns: Vec<[f32; 3]>,
+    norms: Vec<[f32; 3]>,
+    coords: Vec<[f32; 2]>,
+    mats: Vec<[f32; MATERIAL_CHANNELS]>,
+    ao: Vec<f32>,
+    colors: Vec<[f32; 4]>,
+}
+
+impl From<RawMeshData> for MeshData {
+    fn from(raw: RawMeshData) -> Self {
+        let mut bounds = None;
+        for pos in &raw.posns {
+            extend_bounds(&mut bounds, Vector3::from(*pos));
+        }
+        MeshData {
+            posns: raw.posns.into_iter().map(Position).collect(),
+            norms: raw.norms.into_iter().map(Normal).collect(),
+            coords: raw.coords.into_iter().map(TexCoord).collect(),
+            mats: raw.mats,
+            ao: raw.ao,
+            colors: raw.colors.into_iter().map(Color).collect(),
+            bounds,
+        }
+    }
+}
+
+impl From<MeshData> for RawMeshData {
+    fn from(mesh_data: MeshData) -> Self {
+        RawMeshData {
+            posns: mesh_data.posns.into_iter().map(|p| p.0).collect(),
+            norms: mesh_data.norms.into_iter().map(|n| n.0).collect(),
+            coords: mesh_data.coords.into_iter().map(|c| c.0).collect(),
+            mats: mesh_data.mats,
+            ao: mesh_data.ao,
+            colors: mesh_data.colors.into_iter().map(|c| c.0).collect(),
+        }
+    }
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(from = "RawMeshData", into = "RawMeshData")]
 pub struct MeshData {
     posns: Vec<Position>,
     norms: Vec<Normal>,
     coords: Vec<TexCoord>,
+    /// Per-vertex material weights, empty unless meshed with a material matrix.
+    mats: Vec<[f32; MATERIAL_CHANNELS]>,
+    /// Per-vertex ambient light in [0, 1], empty unless baked.
+    ao: Vec<f32>,
+    /// Per-vertex RGBA colors, empty unless painted.
+    colors: Vec<Color>,
+    /// Smallest and largest vertex coordinates, grown as vertices are added.
+    bounds: Option<(Vector3<f32>, Vector3<f32>)>,
+}
+
+/// Size of a mesh, see `MeshData::stats`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MeshStats {
+    pub triangle_count: usize,
+    pub vertex_count: usize,
+    /// Smallest and largest vertex coordinates, both zero for an empty mesh.
+    pub bounds: (Vector3<f32>, Vector3<f32>),
+}
+
+/// Which sides of a chunk `MeshData::add_skirts` puts skirts on, usually the ones whose
+/// neighbour is meshed at a different level of detail.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SkirtFaces {
+    pub neg_x: bool,
+    pub pos_x: bool,
+    pub neg_y: bool,
+    pub pos_y: bool,
+    pub neg_z: bool,
+    pub pos_z: bool,
+}
+
+impl SkirtFaces {
+    pub fn all() -> Self {
+        SkirtFaces {
+            neg_x: true,
+            pos_x: true,
+            neg_y: true,
+            pos_y: true,
+            neg_z: true,
+            pos_z: true,
+        }
+    }
+
+    pub fn any(&self) -> bool {
+        return self.neg_x || self.pos_x || self.neg_y || self.pos_y || self.neg_z || self.pos_z;
+    }
+
+    /// Outward direction of every flagged face whose plane contains both points, or zero
+    /// if the edge isn't on one.
+    fn boundary(&self, a: &Vector3<f32>, b: &Vector3<f32>, size: &Vector3<f32>) -> Vector3<f32> {
+        let flags = [
+            (self.neg_x, self.pos_x),
+            (self.neg_y, self.pos_y),
+            (self.neg_z, self.pos_z),
+        ];
+        let mut outward = Vector3::zeros();
+        for (axis, &(neg, pos)) in flags.iter().enumerate() {
+            let epsilon = size[axis] * SKIRT_EPSILON;
+            let on = |plane: f32| {
+                (a[axis] - plane).abs() <= epsilon && (b[axis] - plane).abs() <= epsilon
+            };
+            if neg && on(0.0) {
+                outward[axis] -= 1.0;
+            }
+            if pos && on(size[axis]) {
+                outward[axis] += 1.0;
+            }
+        }
+        return outward;
+    }
+}
+
+/// Share of the chunk size a vertex may be off a chunk face and still count as on it.
+const SKIRT_EPSILON: f32 = 1e-4;
+
+/// Returned when a mesh has more vertices than a `u16` index buffer can address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IndexOverflow {
+    pub vertices: usize,
 }
 
+impl fmt::Display for IndexOverflow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "mesh has {} vertices, more than a u16 index buffer can address",
+            self.vertices
+        )
+    }
+}
+
+impl Error for IndexOverflow {}
+
 impl MeshData {
-    pub fn get_mesh_data(self) -> (Vec<u16>, Vec<Position>, Vec<Normal>, Vec<TexCoord>) {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of vertices in the mesh.
+    pub fn len(&self) -> usize {
+        return self.posns.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.posns.is_empty();
+    }
+
+    /// Triangle and vertex counts and the bounding box of the mesh, e.g. for profiling,
+    /// picking a level of detail or culling. The bounds are kept as the mesh is built, so
+    /// this is cheap.
+    pub fn stats(&self) -> MeshStats {
+        return MeshStats {
+            triangle_count: self.posns.len() / 3,
+            vertex_count: self.posns.len(),
+            bounds: self.bounds.unwrap_or((Vector3::zeros(), Vector3::zeros())),
+        };
+    }
+
+    pub fn positions(&self) -> &[Position] {
+        return &self.posns;
+    }
+
+    pub fn normals(&self) -> &[Normal] {
+        return &self.norms;
+    }
+
+    pub fn tex_coords(&self) -> &[TexCoord] {
+        return &self.coords;
+    }
+
+    /// How much each of the `MATERIAL_CHANNELS` materials contributes at each vertex,
+    /// blended from the cube corners along the vertex's edge, for texture splatting. Empty
+    /// unless the mesh was built by `get_mesh_data_with_materials`.
+    pub fn material_weights(&self) -> &[[f32; MATERIAL_CHANNELS]] {
+        return &self.mats;
+    }
+
+    /// Per-vertex colors, empty unless the mesh was painted by `paint`.
+    pub fn colors(&self) -> &[Color] {
+        return &self.colors;
+    }
+
+    /// Colors every vertex with `color_fn`. It receives the vertex's world position (its
+    /// mesh position plus `origin`) and its normal, and returns an RGBA color.
+    pub fn paint<F>(&mut self, origin: Vector3<f32>, color_fn: F)
+    where
+        F: Fn(Vector3<f32>, Vector3<f32>) -> [f32; 4],
+    {
+        self.colors = self
+            .posns
+            .iter()
+            .zip(&self.norms)
+            .map(|(p, n)| Color(color_fn(Vector3::from(p.0) + origin, Vector3::from(n.0))))
+            .collect();
+    }
+
+    /// Per-vertex tangents for normal mapping, with the bitangent sign in `w`.
+    ///
+    /// Tangents follow the texture coordinates of each triangle. Where those don't span
+    /// the triangle, such as with `UvMode::None`, a tangent perpendicular to the normal is
+    /// picked from the triangle geometry instead, so the basis is still stable.
+    pub fn tangents(&self) -> Vec<Tangent> {
+        let mut tangents = Vec::with_capacity(self.posns.len());
+        for i in 0..self.posns.len() / 3 {
+            let p: Vec<Vector3<f32>> = (0..3)
+                .map(|j| Vector3::from(self.posns[i * 3 + j].0))
+                .collect();
+            let uv: Vec<Vector2<f32>> = (0..3)
+                .map(|j| Vector2::from(self.coords[i * 3 + j].0))
+                .collect();
+            let (edge1, edge2) = (p[1] - p[0], p[2] - p[0]);
+            let (duv1, duv2) = (uv[1] - uv[0], uv[2] - uv[0]);
+            let det = duv1.x * duv2.y - duv2.x * duv1.y;
+            let uv_basis = if det.abs() > DEGENERATE_EPSILON {
+                let tangent = (edge1 * duv2.y - edge2 * duv1.y) / det;
+                let bitangent = (edge2 * duv1.x - edge1 * duv2.x) / det;
+                Some((tangent, bitangent))
+            } else {
+                None
+            };
+
+            for j in 0..3 {
+                let normal = Vector3::from(self.norms[i * 3 + j].0);
+                let (tangent, bitangent) = match uv_basis {
+                    Some(basis) => basis,
+                    None => (geometric_tangent(&normal, &edge1), normal.cross(&edge1)),
+                };
+                // Gram-Schmidt, so the tangent is perpendicular to the vertex normal
+                let tangent = (tangent - normal * normal.dot(&tangent))
+                    .try_normalize(DEGENERATE_EPSILON)
+                    .unwrap_or_else(|| geometric_tangent(&normal, &edge1));
+                let handedness = if normal.cross(&tangent).dot(&bitangent) < 0.0 {
+                    -1.0
+                } else {
+                    1.0
+                };
+                tangents.push(Tangent {
+                    0: [tangent.x, tangent.y, tangent.z, handedness],
+                });
+            }
+        }
+        return tangents;
+    }
+
+    /// Ambient light reaching each vertex, from 1 for an open surface down to 0 deep in a
+    /// crevice. Empty unless `bake_ambient_occlusion` was called.
+    pub fn ambient_occlusion(&self) -> &[f32] {
+        return &self.ao;
+    }
+
+    /// Estimates how enclosed each vertex is from the share of solid points within
+    /// `AO_RADIUS` grid points of it in `matrix`, the density matrix the mesh was built
+    /// from with the same `scale` and `options`. A flat surface has about half of its
+    /// neighbourhood solid and stays fully lit, more than that darkens the vertex, by up
+    /// to `strength`.
+    pub fn bake_ambient_occlusion(
+        &mut self,
+        matrix: &Matrix3D,
+        scale: Vector3<f32>,
+        options: &MeshOptions,
+        strength: f32,
+    ) {
+        let radius = AO_RADIUS as isize;
+        let size = Vector3::new(matrix.x(), matrix.y(), matrix.z()).map(|v| v as isize);
+        self.ao = self
+            .posns
+            .iter()
+            .map(|p| {
+                let grid = Vector3::from(p.0)
+                    .component_div(&scale)
+                    .map(|v| v.round() as isize + options.margin as isize);
+                let mut solid = 0;
+                let mut total = 0;
+                for z in -radius..=radius {
+                    for y in -radius..=radius {
+                        for x in -radius..=radius {
+                            let point = grid + Vector3::new(x, y, z);
+                            if (0..3).any(|axis| point[axis] < 0 || point[axis] >= size[axis]) {
+                                continue;
+                            }
+                            total += 1;
+                            if matrix.get(point.map(|v| v as usize)) < options.cutoff {
+                                solid += 1;
+                            }
+                        }
+                    }
+                }
+                if total == 0 {
+                    return 1.0;
+                }
+                let enclosed = (solid as f32 / total as f32 - 0.5).max(0.0) * 2.0;
+                1.0 - (enclosed * strength).min(1.0)
+            })
+            .collect();
+    }
+
+    /// Hides the cracks between chunks meshed at different levels of detail by hanging a
+    /// skirt `depth` units straight down from every triangle edge that lies on one of the
+    /// flagged `faces` of a chunk spanning `size` from the origin. The top of a skirt reuses
+    /// the edge's own vertex positions exactly, and its vertices copy the edge's normals,
+    /// texture coordinates, materials and ambient occlusion so it shades like the surface
+    /// it hangs from. Skirts face out of the chunk, or the way the surface faces on the
+    /// top and bottom faces.
+    ///
+    /// Call it before `append`, as the positions must still be relative to the chunk.
+    pub fn add_skirts(&mut self, size: Vector3<f32>, faces: SkirtFaces, depth: f32) {
+        if !faces.any() || depth <= 0.0 {
+            return;
+        }
+        let drop = Vector3::new(0.0, depth, 0.0);
+        let triangles = self.posns.len() / 3;
+        for tri in 0..triangles {
+            for edge in 0..3 {
+                let ia = tri * 3 + edge;
+                let ib = tri * 3 + (edge + 1) % 3;
+                let a = Vector3::from(self.posns[ia].0);
+                let b = Vector3::from(self.posns[ib].0);
+                let mut outward = faces.boundary(&a, &b, &size);
+                if outward == Vector3::zeros() {
+                    continue;
+                }
+                outward.y = 0.0;
+                if outward == Vector3::zeros() {
+                    // On the top or bottom face only, follow the surface instead
+                    outward = Vector3::from(self.norms[ia].0) + Vector3::from(self.norms[ib].0);
+                    outward.y = 0.0;
+                }
+                // The skirt's front is (b - a) x down, flip the edge if that faces inwards
+                let (ia, ib) = if (b - a).cross(&-Vector3::y()).dot(&outward) >= 0.0 {
+                    (ia, ib)
+                } else {
+                    (ib, ia)
+                };
+                let a_low = Position((Vector3::from(self.posns[ia].0) - drop).into());
+                let b_low = Position((Vector3::from(self.posns[ib].0) - drop).into());
+                let a_top = self.posns[ia];
+                let b_top = self.posns[ib];
+                let corners = [
+                    (ia, a_top),
+                    (ib, b_top),
+                    (ib, b_low),
+                    (ia, a_top),
+                    (ib, b_low),
+                    (ia, a_low),
+                ];
+                for &(source, pos) in corners.iter() {
+                    self.posns.push(pos);
+                    extend_bounds(&mut self.bounds, Vector3::from(pos.0));
+                    self.norms.push(self.norms[source]);
+                    self.coords.push(self.coords[source]);
+                    if !self.mats.is_empty() {
+                        self.mats.push(self.mats[source]);
+                    }
+                    if !self.ao.is_empty() {
+                        self.ao.push(self.ao[source]);
+                    }
+                    if !self.colors.is_empty() {
+                        self.colors.push(self.colors[source]);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Merges another mesh into this one, e.g. to draw several distant chunks at once.
+    /// The positions of `other` are kept as they are, so both meshes should share an origin.
+    pub fn append(&mut self, mut other: MeshData) {
+        if let Some((min, max)) = other.bounds {
+            extend_bounds(&mut self.bounds, min);
+            extend_bounds(&mut self.bounds, max);
+        }
+        self.posns.append(&mut other.posns);
+        self.norms.append(&mut other.norms);
+        self.coords.append(&mut other.coords);
+        self.mats.append(&mut other.mats);
+        self.ao.append(&mut other.ao);
+        self.colors.append(&mut other.colors);
+    }
+
+    pub fn get_mesh_data(
+        self,
+    ) -> Result<(Vec<u16>, Vec<Position>, Vec<Normal>, Vec<TexCoord>), IndexOverflow> {
+        if self.posns.len() > u16::MAX as usize + 1 {
+            return Err(IndexOverflow {
+                vertices: self.posns.len(),
+            });
+        }
+        return Ok((
+            (0..self.posns.len()).map(|i| i as u16).collect(),
+            self.posns,
+            self.norms,
+            self.coords,
+        ));
+    }
+
+    /// Merges vertices that share a position, returning the unique points and the triangles
+    /// indexing into them.
+    pub fn indexed_triangles(&self) -> (Vec<Point3<f32>>, Vec<Point3<usize>>) {
+        let mut lookup: HashMap<[u32; 3], usize> = HashMap::new();
+        let mut points = vec![];
+        let mut vertex_indices = Vec::with_capacity(self.posns.len());
+        for p in &self.posns {
+            let key = [p.0[0].to_bits(), p.0[1].to_bits(), p.0[2].to_bits()];
+            let index = *lookup.entry(key).or_insert_with(|| {
+                points.push(Point3::new(p.0[0], p.0[1], p.0[2]));
+                points.len() - 1
+            });
+            vertex_indices.push(index);
+        }
+        let indices = vertex_indices
+            .chunks(3)
+            .map(|tri| Point3::new(tri[0], tri[1], tri[2]))
+            .collect();
+        return (points, indices);
+    }
+
+    /// Builds a concave trimesh collision shape from the indexed triangles of the mesh, so
+    /// the shape isn't a triangle soup. Returns `None` for an empty mesh.
+    pub fn to_trimesh_shape(&self) -> Option<ShapeDesc<f32>> {
+        if self.is_empty() {
+            return None;
+        }
+        let (points, indices) = self.indexed_triangles();
+        return Some(ShapeDesc::TriMesh { indices, points });
+    }
+
+    /// Builds an amethyst mesh with separate position, normal and texture coordinate
+    /// buffers and a `u32` triangle list index buffer. Returns `None` for an empty mesh,
+    /// which some backends refuse to draw.
+    pub fn into_mesh_builder(self) -> Option<MeshBuilder<'static>> {
+        if self.is_empty() {
+            return None;
+        }
+        let (indices, posns, norms, coords) = self.get_mesh_data_u32();
+        return Some(
+            MeshBuilder::new()
+                .with_vertices(posns)
+                .with_vertices(norms)
+                .with_vertices(coords)
+                .with_indices(Indices::U32(indices.into()))
+                .with_prim_type(Primitive::TriangleList),
+        );
+    }
+
+    /// Like `into_mesh_builder`, with a tangent buffer as well for normal-mapped materials.
+    pub fn into_mesh_builder_with_tangents(self) -> Option<MeshBuilder<'static>> {
+        if self.is_empty() {
+            return None;
+        }
+        let tangents = self.tangents();
+        let (indices, posns, norms, coords) = self.get_mesh_data_u32();
+        return Some(
+            MeshBuilder::new()
+                .with_vertices(posns)
+                .with_vertices(norms)
+                .with_vertices(tangents)
+                .with_vertices(coords)
+                .with_indices(Indices::U32(indices.into()))
+                .with_prim_type(Primitive::TriangleList),
+        );
+    }
+
+    /// Like `into_mesh_builder`, with a color buffer as well for vertex colored materials.
+    /// Returns `None` for an empty or unpainted mesh.
+    pub fn into_mesh_builder_with_colors(mut self) -> Option<MeshBuilder<'static>> {
+        if self.is_empty() || self.colors.len() != self.posns.len() {
+            return None;
+        }
+        let colors = std::mem::take(&mut self.colors);
+        let (indices, posns, norms, coords) = self.get_mesh_data_u32();
+        return Some(
+            MeshBuilder::new()
+                .with_vertices(posns)
+                .with_vertices(norms)
+                .with_vertices(colors)
+                .with_vertices(coords)
+                .with_indices(Indices::U32(indices.into()))
+                .with_prim_type(Primitive::TriangleList),
+        );
+    }
+
+    /// Loads the mesh as an asset, or returns `None` if it has no vertices.
+    pub fn into_mesh(self, loader: &Loader, storage: &AssetStorage<Mesh>) -> Option<Handle<Mesh>> {
+        let builder = self.into_mesh_builder()?;
+        return Some(loader.load_from_data(types::MeshData(builder), (), storage));
+    }
+
+    pub fn get_mesh_data_u32(self) -> (Vec<u32>, Vec<Position>, Vec<Normal>, Vec<TexCoord>) {
         return (
-            (0..(self.posns.len() as u16)).collect(),
+            (0..(self.posns.len() as u32)).collect(),
             self.posns,
             self.norms,
             self.coords,
         );
     }
 }
+
+/// A unit vector perpendicular to `normal`, lying along the triangle `edge` when possible.
+fn geometric_tangent(normal: &Vector3<f32>, edge: &Vector3<f32>) -> Vector3<f32> {
+    let tangent = edge - normal * normal.dot(edge);
+    if let Some(tangent) = tangent.try_normalize(DEGENERATE_EPSILON) {
+        return tangent;
+    }
+    // Use whichever axis is least aligned with the normal
+    let axis = if normal.x.abs() < 0.9 { Vector3::x() } else { Vector3::y() };
+    return (axis - normal * normal.dot(&axis)).normalize();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A `size`³ matrix of the distance to a sphere of `radius` in its middle, solid inside.
+    fn sphere(size: usize, radius: f32) -> Matrix3D {
+        let mut matrix = Matrix3D::new(size, size, size);
+        let centre = Vector3::repeat((size - 1) as f32 / 2.0);
+        for (pos, val) in matrix.iter_mut() {
+            *val = (pos.map(|v| v as f32) - centre).norm() - radius;
+        }
+        matrix
+    }
+
+    /// Alternating solid and open points, the densest surface a matrix can hold.
+    fn checkerboard(size: usize) -> Matrix3D {
+        let mut matrix = Matrix3D::new(size, size, size);
+        for (pos, val) in matrix.iter_mut() {
+            *val = if (pos.x + pos.y + pos.z) % 2 == 0 { -1.0 } else { 1.0 };
+        }
+        matrix
+    }
+
+    #[test]
+    fn meshes_with_the_embedded_triangulation() {
+        let table = parse_triangulation(DEFAULT_TRIANGULATION).unwrap();
+        assert_eq!(table.triangulation_table.len(), 256);
+        assert!(!get_mesh_data(&sphere(8, 2.5), Vector3::repeat(1.0), 0.0).is_empty());
+    }
+
+    #[test]
+    fn dense_meshes_overflow_u16_indices_but_not_u32() {
+        let mesh_data = get_mesh_data(&checkerboard(24), Vector3::repeat(1.0), 0.0);
+        let vertices = mesh_data.len();
+        assert!(vertices > u16::MAX as usize + 1);
+        assert_eq!(mesh_data.clone().get_mesh_data().err(), Some(IndexOverflow { vertices }));
+        let (indices, posns, _, _) = mesh_data.get_mesh_data_u32();
+        assert_eq!(indices.len(), posns.len());
+        assert!(indices.iter().enumerate().all(|(i, &index)| index as usize == i));
+    }
+
+    #[test]
+    fn small_meshes_get_monotone_u16_indices() {
+        let mesh_data = get_mesh_data(&checkerboard(4), Vector3::repeat(1.0), 0.0);
+        let (indices, posns, _, _) = mesh_data.get_mesh_data().ok().unwrap();
+        assert!(!indices.is_empty());
+        assert!(indices.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(indices.iter().all(|&index| (index as usize) < posns.len()));
+    }
+
+    fn assert_finite(mesh_data: &MeshData) {
+        for (pos, normal) in mesh_data.positions().iter().zip(mesh_data.normals()) {
+            assert!(pos.0.iter().chain(&normal.0).all(|v| v.is_finite()));
+        }
+    }
+
+    #[test]
+    fn corners_at_the_cutoff_give_finite_vertices() {
+        // The middle layer and one point below it sit exactly on the cutoff
+        let mut matrix = Matrix3D::new(3, 3, 3);
+        for (pos, val) in matrix.iter_mut() {
+            *val = pos.y as f32 - 1.0;
+        }
+        matrix.set(Vector3::new(1, 0, 1), 0.0);
+        let polygonizations = [Polygonization::MarchingCubes, Polygonization::MarchingTetrahedra];
+        for &normal_mode in &[NormalMode::Flat, NormalMode::Smooth] {
+            for &polygonization in &polygonizations {
+                let options = MeshOptions {
+                    normal_mode,
+                    polygonization,
+                    ..MeshOptions::default()
+                };
+                let mesh_data = get_mesh_data_with(&matrix, Vector3::repeat(1.0), &options);
+                assert!(!mesh_data.is_empty());
+                assert_finite(&mesh_data);
+            }
+        }
+    }
+
+    /// Size of the box around a mesh.
+    fn extent(mesh_data: &MeshData) -> Vector3<f32> {
+        let (min, max) = mesh_data.stats().bounds;
+        max - min
+    }
+
+    #[test]
+    fn lower_cutoffs_shrink_the_solid_volume() {
+        // Densities below the cutoff are solid, so the sphere's surface sits where the
+        // distance to it equals the cutoff
+        let matrix = sphere(12, 3.0);
+        let scale = Vector3::repeat(1.0);
+        let low = extent(&get_mesh_data(&matrix, scale, -1.0));
+        let default = extent(&get_mesh_data(&matrix, scale, 0.0));
+        let high = extent(&get_mesh_data(&matrix, scale, 1.0));
+        for axis in 0..3 {
+            assert!(low[axis] < default[axis] && default[axis] < high[axis]);
+            assert!((default[axis] - 6.0).abs() < 0.5);
+        }
+    }
+
+    #[test]
+    fn equal_corner_densities_give_finite_vertices() {
+        // The two layers straddle the cutoff but differ by less than f32::EPSILON, so the
+        // crossing falls back to the middle of each edge
+        let mut matrix = Matrix3D::new(2, 2, 2);
+        for (pos, val) in matrix.iter_mut() {
+            *val = if pos.y == 0 { -1e-8 } else { 0.0 };
+        }
+        let mesh_data = get_mesh_data(&matrix, Vector3::repeat(1.0), 0.0);
+        assert!(!mesh_data.is_empty());
+        assert_finite(&mesh_data);
+        assert!(mesh_data.positions().iter().all(|pos| (pos.0[1] - 0.5).abs() < 1e-6));
+    }
+
+    #[test]
+    fn trimesh_shapes_index_every_triangle() {
+        let mesh_data = get_mesh_data(&sphere(10, 3.0), Vector3::repeat(0.5), 0.0);
+        assert_eq!(mesh_data.len() % 3, 0);
+        let (points, indices) = match mesh_data.to_trimesh_shape() {
+            Some(ShapeDesc::TriMesh { points, indices }) => (points, indices),
+            _ => panic!("expected a trimesh"),
+        };
+        assert_eq!(indices.len() * 3, mesh_data.len());
+        // Shared corners are merged
+        assert!(points.len() < mesh_data.len());
+        for tri in &indices {
+            assert!(tri.iter().all(|&index| index < points.len()));
+        }
+        assert!(MeshData::default().to_trimesh_shape().is_none());
+    }
+
+    #[test]
+    fn triplanar_uvs_vary_across_a_slanted_surface() {
+        let mut matrix = Matrix3D::new(6, 6, 6);
+        for (pos, val) in matrix.iter_mut() {
+            *val = pos.y as f32 - 0.5 * pos.x as f32 - 1.2;
+        }
+        let options = MeshOptions {
+            uv_mode: UvMode::Triplanar { scale: 0.5 },
+            ..MeshOptions::default()
+        };
+        let mesh_data = get_mesh_data_with(&matrix, Vector3::repeat(1.0), &options);
+        let coords = mesh_data.tex_coords();
+        assert_eq!(coords.len(), mesh_data.len());
+        for axis in 0..2 {
+            let first = coords[0].0[axis];
+            assert!(coords.iter().any(|coord| (coord.0[axis] - first).abs() > 0.1));
+        }
+        let untextured = get_mesh_data_with(&matrix, Vector3::repeat(1.0), &MeshOptions::default());
+        assert!(untextured.tex_coords().iter().all(|coord| coord.0 == [0.0, 0.0]));
+    }
+
+    #[test]
+    fn ambient_occlusion_is_darker_inside_a_pocket() {
+        // A flat floor at y = 7.5 with a hemispherical pocket dug into its middle
+        let mut matrix = Matrix3D::new(16, 16, 16);
+        let centre = Vector3::new(8.0, 7.5, 8.0);
+        for (pos, val) in matrix.iter_mut() {
+            let pos = pos.map(|v| v as f32);
+            *val = (pos.y - 7.5).max(3.0 - (pos - centre).norm());
+        }
+        let scale = Vector3::repeat(1.0);
+        let options = MeshOptions::default();
+        let mut mesh_data = get_mesh_data_with(&matrix, scale, &options);
+        mesh_data.bake_ambient_occlusion(&matrix, scale, &options, 1.0);
+        let darkest = |keep: &dyn Fn(&Position) -> bool| {
+            mesh_data
+                .positions()
+                .iter()
+                .zip(mesh_data.ambient_occlusion())
+                .filter(|(pos, _)| keep(pos))
+                .map(|(_, &ao)| ao)
+                .fold(1.0, f32::min)
+        };
+        let pocket = darkest(&|pos| pos.0[1] < 6.5);
+        let exposed = darkest(&|pos| pos.0[0] < 3.0 && pos.0[2] < 3.0);
+        assert!((exposed - 1.0).abs() < 1e-6);
+        assert!(pocket < exposed);
+    }
+
+    #[test]
+    fn near_equal_corners_stay_inside_the_cube() {
+        let pairs = [
+            (-1e-8, 0.0),
+            (-std::f32::MIN_POSITIVE, 0.0),
+            (-1e-30, 1e-30),
+            (-1e-7, 1e-7),
+            (-1.0, 1e-30),
+            (-1e30, 1e30),
+            (-1e30, 0.0),
+        ];
+        let polygonizations = [Polygonization::MarchingCubes, Polygonization::MarchingTetrahedra];
+        for &(below, above) in &pairs {
+            // One solid corner under three open ones, so the crossings aren't axis aligned
+            let mut matrix = Matrix3D::new(2, 2, 2);
+            matrix.fill(above);
+            matrix.set(Vector3::new(0, 0, 0), below);
+            for &polygonization in &polygonizations {
+                let options = MeshOptions {
+                    polygonization,
+                    ..MeshOptions::default()
+                };
+                let mesh_data = get_mesh_data_with(&matrix, Vector3::repeat(1.0), &options);
+                assert!(!mesh_data.is_empty());
+                assert_finite(&mesh_data);
+                for pos in mesh_data.positions() {
+                    assert!(pos.0.iter().all(|&v| (0.0..=1.0).contains(&v)));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn stats_match_the_vertices() {
+        let mesh_data = get_mesh_data(&sphere(10, 3.0), Vector3::new(0.5, 1.0, 2.0), 0.0);
+        let stats = mesh_data.stats();
+        assert_eq!(stats.vertex_count, mesh_data.positions().len());
+        assert_eq!(stats.triangle_count, mesh_data.positions().len() / 3);
+        let mut min = Vector3::repeat(std::f32::INFINITY);
+        let mut max = Vector3::repeat(std::f32::NEG_INFINITY);
+        for pos in mesh_data.positions() {
+            min = min.inf(&Vector3::from(pos.0));
+            max = max.sup(&Vector3::from(pos.0));
+        }
+        assert_eq!(stats.bounds, (min, max));
+
+        let empty = MeshData::default().stats();
+        assert_eq!((empty.triangle_count, empty.vertex_count), (0, 0));
+        assert_eq!(empty.bounds, (Vector3::zeros(), Vector3::zeros()));
+    }
+
+    #[test]
+    fn broken_triangulations_are_rejected() {
+        let mut table = parse_triangulation(DEFAULT_TRIANGULATION).unwrap();
+        table.triangulation_table.pop();
+        match table.check() {
+            Err(TriangulationError::Entries { field, expected, found }) => {
+                assert_eq!(field, "triangulation_table");
+                assert_eq!((expected, found), (256, 255));
+            }
+            _ => panic!("a table missing a case was accepted"),
+        }
+        let missing = env::temp_dir().join("kyro-missing-triangulation.ron");
+        match load_triangulation(missing.to_str().unwrap()) {
+            Err(TriangulationError::Io(_)) => {}
+            _ => panic!("a missing table was loaded"),
+        }
+        match parse_triangulation("(triangulation_table: [") {
+            Err(TriangulationError::Parse(_)) => {}
+            _ => panic!("a truncated file was parsed"),
+        }
+    }
+
+    #[test]
+    fn tiny_scales_keep_every_triangle() {
+        let matrix = sphere(8, 2.5);
+        let full = get_mesh_data(&matrix, Vector3::repeat(1.0), 0.0);
+        let tiny = get_mesh_data(&matrix, Vector3::repeat(1e-4), 0.0);
+        assert!(!full.is_empty());
+        assert_eq!(tiny.len(), full.len());
+        assert_finite(&tiny);
+        for (small, big) in tiny.positions().iter().zip(full.positions()) {
+            assert!((small.0[0] * 1e4 - big.0[0]).abs() < 1e-2);
+        }
+    }
+}
@@ -1,4 +1,24 @@
 use amethyst::core::math::Vector3;
+use std::{error::Error, fmt};
+
+/// Returned by `Matrix3D::try_set` when the position lies outside the matrix.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutOfBounds {
+    pub position: Vector3<usize>,
+    pub size: Vector3<usize>,
+}
+
+impl fmt::Display for OutOfBounds {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "position ({}, {}, {}) is outside a {}x{}x{} matrix",
+            self.position.x, self.position.y, self.position.z, self.size.x, self.size.y, self.size.z
+        )
+    }
+}
+
+impl Error for OutOfBounds {}
 
 pub struct Matrix3D {
     x: usize,
@@ -17,6 +37,33 @@ impl Matrix3D {
         }
     }
 
+    /// Builds a matrix from its values in `as_slice` order, or `None` if there aren't
+    /// `x * y * z` of them.
+    pub fn from_vec(x: usize, y: usize, z: usize, elems: Vec<f32>) -> Option<Self> {
+        if elems.len() != x * y * z {
+            return None;
+        }
+        return Some(Matrix3D { x, y, z, elems });
+    }
+
+    /// All values, x varying fastest, then y, then z.
+    pub fn as_slice(&self) -> &[f32] {
+        return &self.elems;
+    }
+
+    /// Changes the dimensions of the matrix, keeping its allocation when it is large
+    /// enough. The values are reset to zero if the dimensions change.
+    pub fn resize(&mut self, x: usize, y: usize, z: usize) {
+        if (x, y, z) == (self.x, self.y, self.z) {
+            return;
+        }
+        self.x = x;
+        self.y = y;
+        self.z = z;
+        self.elems.clear();
+        self.elems.resize(x * y * z, 0.0);
+    }
+
     fn index(&self, vec: Vector3<usize>/*x: usize, y: usize, z: usize*/) -> usize {
         return vec.z * self.x * self.y + vec.y * self.x + vec.x;
     }
@@ -30,6 +77,114 @@ impl Matrix3D {
         self.elems[index] = val;
     }
 
+    /// Position of the value at `index` in `as_slice`.
+    fn position(&self, index: usize) -> Vector3<usize> {
+        let plane = self.x * self.y;
+        return Vector3::new(index % self.x, index % plane / self.x, index / plane);
+    }
+
+    /// Every position with its value, in `as_slice` order.
+    pub fn iter(&self) -> impl Iterator<Item = (Vector3<usize>, f32)> + '_ {
+        return self
+            .elems
+            .iter()
+            .enumerate()
+            .map(move |(i, &val)| (self.position(i), val));
+    }
+
+    /// Every position with a mutable reference to its value, in `as_slice` order.
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (Vector3<usize>, &mut f32)> + '_ {
+        let (x, plane) = (self.x, self.x * self.y);
+        return self
+            .elems
+            .iter_mut()
+            .enumerate()
+            .map(move |(i, val)| (Vector3::new(i % x, i % plane / x, i / plane), val));
+    }
+
+    /// A matrix of the same dimensions with `f` applied to every value.
+    pub fn map(&self, f: impl Fn(f32) -> f32) -> Self {
+        Matrix3D {
+            x: self.x,
+            y: self.y,
+            z: self.z,
+            elems: self.elems.iter().map(|&val| f(val)).collect(),
+        }
+    }
+
+    pub fn fill(&mut self, val: f32) {
+        for elem in self.elems.iter_mut() {
+            *elem = val;
+        }
+    }
+
+    pub fn contains(&self, vec: Vector3<usize>) -> bool {
+        return vec.x < self.x && vec.y < self.y && vec.z < self.z;
+    }
+
+    pub fn try_get(&self, vec: Vector3<usize>) -> Option<f32> {
+        if !self.contains(vec) {
+            return None;
+        }
+        return Some(self.get(vec));
+    }
+
+    pub fn try_set(&mut self, vec: Vector3<usize>, val: f32) -> Result<(), OutOfBounds> {
+        if !self.contains(vec) {
+            return Err(OutOfBounds {
+                position: vec,
+                size: self.dimensions(),
+            });
+        }
+        self.set(vec, val);
+        return Ok(());
+    }
+
+    /// A copy where every value is the average of the values within `radius` steps of it
+    /// along each axis, a box blur applied one axis at a time. Positions past the border
+    /// read the nearest border value.
+    pub fn smoothed(&self, radius: usize) -> Self {
+        let mut result = self.map(|val| val);
+        if radius == 0 {
+            return result;
+        }
+        let dims = self.dimensions();
+        let mut line = vec![];
+        for axis in 0..3 {
+            let len = dims[axis];
+            if len == 0 {
+                continue;
+            }
+            let source = result.map(|val| val);
+            for (pos, val) in result.iter_mut() {
+                line.clear();
+                let mut sample = pos;
+                for offset in -(radius as isize)..=radius as isize {
+                    let step = (pos[axis] as isize + offset).max(0).min(len as isize - 1);
+                    sample[axis] = step as usize;
+                    line.push(source.get(sample));
+                }
+                *val = line.iter().sum::<f32>() / line.len() as f32;
+            }
+        }
+        return result;
+    }
+
+    /// Smallest and largest value in the matrix.
+    pub fn min_max(&self) -> (f32, f32) {
+        let mut min = std::f32::INFINITY;
+        let mut max = std::f32::NEG_INFINITY;
+        for &val in &self.elems {
+            min = min.min(val);
+            max = max.max(val);
+        }
+        return (min, max);
+    }
+
+    pub fn dimensions(&self) -> Vector3<usize> {
+        return Vector3::new(self.x, self.y, self.z);
+    }
+
     pub fn x(&self) -> usize {
         return self.x;
     }
@@ -42,3 +197,73 @@ impl Matrix3D {
         return self.z;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn try_get_and_try_set_stop_at_the_boundary() {
+        let mut matrix = Matrix3D::new(2, 3, 4);
+        let last = Vector3::new(1, 2, 3);
+        assert_eq!(matrix.try_set(last, 5.0), Ok(()));
+        assert_eq!(matrix.try_get(last), Some(5.0));
+
+        for axis in 0..3 {
+            let mut past = last;
+            past[axis] += 1;
+            assert_eq!(matrix.try_get(past), None);
+            assert_eq!(
+                matrix.try_set(past, 1.0),
+                Err(OutOfBounds {
+                    position: past,
+                    size: Vector3::new(2, 3, 4),
+                })
+            );
+        }
+        assert_eq!(matrix.min_max(), (0.0, 5.0));
+    }
+
+    #[test]
+    fn iteration_runs_x_fastest() {
+        let mut matrix = Matrix3D::new(3, 2, 2);
+        for (i, (_, val)) in matrix.iter_mut().enumerate() {
+            *val = i as f32;
+        }
+        let mut expected = vec![];
+        for z in 0..2 {
+            for y in 0..2 {
+                for x in 0..3 {
+                    expected.push(Vector3::new(x, y, z));
+                }
+            }
+        }
+        let positions: Vec<Vector3<usize>> = matrix.iter().map(|(pos, _)| pos).collect();
+        assert_eq!(positions, expected);
+        for (i, &pos) in expected.iter().enumerate() {
+            assert_eq!(matrix.get(pos), i as f32);
+            assert_eq!(matrix.as_slice()[i], i as f32);
+        }
+
+        let doubled = matrix.map(|val| val * 2.0);
+        assert_eq!(doubled.dimensions(), Vector3::new(3, 2, 2));
+        assert!(doubled.iter().all(|(pos, val)| val == matrix.get(pos) * 2.0));
+        matrix.fill(7.0);
+        assert_eq!(matrix.min_max(), (7.0, 7.0));
+    }
+
+    #[test]
+    fn smoothing_attenuates_a_spike() {
+        let mut matrix = Matrix3D::new(5, 5, 5);
+        let middle = Vector3::new(2, 2, 2);
+        matrix.set(middle, 27.0);
+        let smoothed = matrix.smoothed(1);
+        // Spread evenly over the 3x3x3 box around it
+        assert!((smoothed.get(middle) - 1.0).abs() < 1e-5);
+        assert!((smoothed.get(Vector3::new(1, 3, 1)) - 1.0).abs() < 1e-5);
+        assert_eq!(smoothed.get(Vector3::new(0, 2, 2)), 0.0);
+        let total: f32 = smoothed.as_slice().iter().sum();
+        assert!((total - 27.0).abs() < 1e-4);
+        assert_eq!(matrix.smoothed(0).as_slice(), matrix.as_slice());
+    }
+}
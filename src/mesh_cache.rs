@@ -0,0 +1,68 @@
+use amethyst::core::math::Vector3;
+use std::collections::HashMap;
+
+use crate::marching_cubes::MeshData;
+
+/// Least recently used cache of chunk meshes.
+pub struct MeshCache {
+    capacity: usize,
+    tick: u64,
+    entries: HashMap<Vector3<i32>, (MeshData, u64)>,
+}
+
+impl MeshCache {
+    pub fn new(capacity: usize) -> Self {
+        MeshCache {
+            capacity,
+            tick: 0,
+            entries: HashMap::new(),
+        }
+    }
+
+    pub fn get(&mut self, chunk: Vector3<i32>) -> Option<MeshData> {
+        self.tick += 1;
+        let tick = self.tick;
+        self.entries.get_mut(&chunk).map(|entry| {
+            entry.1 = tick;
+            entry.0.clone()
+        })
+    }
+
+    pub fn insert(&mut self, chunk: Vector3<i32>, mesh_data: MeshData) {
+        if self.capacity == 0 {
+            return;
+        }
+        if !self.entries.contains_key(&chunk) && self.entries.len() >= self.capacity {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.1)
+                .map(|(chunk, _)| *chunk);
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.tick += 1;
+        self.entries.insert(chunk, (mesh_data, self.tick));
+    }
+
+    pub fn invalidate(&mut self, chunk: Vector3<i32>) {
+        self.entries.remove(&chunk);
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn contains(&self, chunk: Vector3<i32>) -> bool {
+        self.entries.contains_key(&chunk)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
@@ -0,0 +1,129 @@
+use crate::terrain::NoiseLayer;
+use noise::{
+    Billow, MultiFractal, NoiseFn, OpenSimplex, Perlin, Point3, RidgedMulti, Seedable, Worley,
+};
+
+/// The base noise function used by a terrain layer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NoiseKind {
+    OpenSimplex,
+    Perlin,
+    /// Several octaves of OpenSimplex noise, see `FractalNoise`.
+    Fbm {
+        octaves: usize,
+        persistence: f64,
+        lacunarity: f64,
+    },
+    /// Cellular noise, useful for caves.
+    Worley,
+    /// Sharp ridges, useful for mountains and cave tunnels.
+    RidgedMulti { octaves: usize },
+    Billow,
+}
+
+impl Default for NoiseKind {
+    fn default() -> Self {
+        NoiseKind::OpenSimplex
+    }
+}
+
+impl NoiseKind {
+    /// Largest magnitude the noise returns, if it's known to stay bounded. Ridged,
+    /// cellular and billowy noise can overshoot [-1, 1].
+    pub fn output_bound(self) -> Option<f32> {
+        match self {
+            NoiseKind::OpenSimplex | NoiseKind::Perlin | NoiseKind::Fbm { .. } => Some(1.0),
+            NoiseKind::Worley | NoiseKind::RidgedMulti { .. } | NoiseKind::Billow => None,
+        }
+    }
+
+    pub fn build(self, seed: u32) -> NoiseLayer {
+        match self {
+            NoiseKind::OpenSimplex => Box::new(OpenSimplex::new().set_seed(seed)),
+            NoiseKind::Perlin => Box::new(Perlin::new().set_seed(seed)),
+            NoiseKind::Fbm {
+                octaves,
+                persistence,
+                lacunarity,
+            } => Box::new(FractalNoise::new(seed, octaves, lacunarity, persistence, 1.0)),
+            NoiseKind::Worley => Box::new(Worley::new().set_seed(seed)),
+            NoiseKind::RidgedMulti { octaves } => {
+                Box::new(RidgedMulti::new().set_seed(seed).set_octaves(octaves))
+            }
+            NoiseKind::Billow => Box::new(Billow::new().set_seed(seed)),
+        }
+    }
+}
+
+/// One noise layer of the terrain: its noise function, how much it contributes to the
+/// density and the scale it is sampled at.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LayerSpec {
+    pub kind: NoiseKind,
+    pub weight: f32,
+    pub scale: f32,
+}
+
+impl LayerSpec {
+    pub fn new(kind: NoiseKind, weight: f32, scale: f32) -> Self {
+        LayerSpec {
+            kind,
+            weight,
+            scale,
+        }
+    }
+}
+
+/// Fractal brownian motion built from several octaves of OpenSimplex noise.
+///
+/// Octave `i` is sampled at `base_scale * lacunarity^i` and weighted by
+/// `persistence^i`, so a lacunarity of 2 doubles the frequency of each octave
+/// while a persistence of 0.5 halves its contribution. The sum is divided by the
+/// total weight to keep the output in the same range as a single noise layer.
+pub struct FractalNoise {
+    sources: Vec<OpenSimplex>,
+    lacunarity: f64,
+    persistence: f64,
+    base_scale: f64,
+}
+
+impl FractalNoise {
+    pub fn new(seed: u32, octaves: usize, lacunarity: f64, persistence: f64, base_scale: f64) -> Self {
+        let sources = (0..octaves)
+            .map(|i| OpenSimplex::new().set_seed(seed.wrapping_add(i as u32)))
+            .collect();
+        FractalNoise {
+            sources,
+            lacunarity,
+            persistence,
+            base_scale,
+        }
+    }
+
+    pub fn octaves(&self) -> usize {
+        self.sources.len()
+    }
+}
+
+impl NoiseFn<Point3<f64>> for FractalNoise {
+    fn get(&self, point: Point3<f64>) -> f64 {
+        let mut frequency = self.base_scale;
+        let mut amplitude = 1.0;
+        let mut total = 0.0;
+        let mut weight = 0.0;
+        for source in &self.sources {
+            total += source.get([
+                point[0] * frequency,
+                point[1] * frequency,
+                point[2] * frequency,
+            ]) * amplitude;
+            weight += amplitude;
+            frequency *= self.lacunarity;
+            amplitude *= self.persistence;
+        }
+        if weight == 0.0 {
+            return 0.0;
+        }
+        total / weight
+    }
+}
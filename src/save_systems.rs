@@ -0,0 +1,145 @@
+use amethyst::{
+    core::{math::Vector3, Time},
+    ecs::prelude::*,
+    shrev::EventChannel,
+};
+use std::{
+    sync::mpsc::{self, Receiver, TryRecvError},
+    thread,
+};
+
+use crate::{
+    terrain::SharedTerrain,
+    world_save::{WorldSave, WorldSaveError, WorldSnapshot},
+};
+
+/// Seconds between autosaves by default.
+const AUTOSAVE_INTERVAL: f32 = 300.0;
+
+/// Sent on an `EventChannel` as saves progress, e.g. to show "Saving…" on a HUD.
+#[derive(Debug, Clone)]
+pub enum SaveEvent {
+    Started,
+    Completed,
+    Failed(String),
+}
+
+type SaveResult = Result<(), WorldSaveError>;
+
+/// Where and how the world is saved, with the save currently running in the background.
+pub struct Autosave {
+    pub save: WorldSave,
+    /// Seconds between autosaves.
+    pub interval: f32,
+    timer: f32,
+    /// The running save and the chunks it holds, flagged unsaved again if it fails.
+    in_flight: Option<(Receiver<SaveResult>, Vec<Vector3<i32>>)>,
+}
+
+impl Autosave {
+    pub fn new(save: WorldSave) -> Self {
+        Autosave {
+            save,
+            interval: AUTOSAVE_INTERVAL,
+            timer: 0.0,
+            in_flight: None,
+        }
+    }
+
+    /// Snapshots the terrain and saves it on a background thread, unless a save is
+    /// already running or nothing changed since the last one. Returns whether it started.
+    pub fn start(&mut self, terrain: &SharedTerrain) -> bool {
+        if self.in_flight.is_some() {
+            return false;
+        }
+        let (snapshot, chunks) = {
+            let mut terrain = terrain.write().unwrap();
+            if !terrain.has_unsaved_edits() {
+                return false;
+            }
+            let chunks = terrain.take_unsaved_edits().into_iter().collect();
+            (WorldSnapshot::capture(&terrain), chunks)
+        };
+        let (sender, receiver) = mpsc::channel();
+        let save = self.save.clone();
+        thread::spawn(move || {
+            let _ = sender.send(snapshot.and_then(|snapshot| save.write(&snapshot)));
+        });
+        self.in_flight = Some((receiver, chunks));
+        return true;
+    }
+
+    /// Result of the background save if it finished since the last call. Chunks of a
+    /// failed save are flagged unsaved again so the next save retries them.
+    pub fn poll(&mut self, terrain: &SharedTerrain) -> Option<SaveResult> {
+        let result = match &self.in_flight {
+            Some((receiver, _)) => match receiver.try_recv() {
+                Ok(result) => result,
+                Err(TryRecvError::Empty) => return None,
+                Err(TryRecvError::Disconnected) => Err(WorldSaveError::Io(
+                    std::io::Error::new(std::io::ErrorKind::Other, "save thread stopped"),
+                )),
+            },
+            None => return None,
+        };
+        let (_, chunks) = self.in_flight.take().unwrap();
+        if result.is_err() {
+            terrain.write().unwrap().mark_unsaved(chunks);
+        }
+        return Some(result);
+    }
+
+    /// Waits for a running save, then saves whatever changed since on this thread. Meant
+    /// for shutdown, where there's no next frame to finish the save on.
+    pub fn finish(&mut self, terrain: &SharedTerrain) -> SaveResult {
+        if let Some((receiver, chunks)) = self.in_flight.take() {
+            if let Ok(Err(_)) | Err(_) = receiver.recv() {
+                terrain.write().unwrap().mark_unsaved(chunks);
+            }
+        }
+        let mut terrain = terrain.write().unwrap();
+        if !terrain.has_unsaved_edits() {
+            return Ok(());
+        }
+        let chunks = terrain.take_unsaved_edits();
+        let result = self.save.save(&terrain);
+        if result.is_err() {
+            terrain.mark_unsaved(chunks);
+        }
+        return result;
+    }
+}
+
+/// Saves the world every `Autosave::interval` seconds on a background thread, skipping
+/// saves when nothing was edited, and sends `SaveEvent`s as saves start and finish. Does
+/// nothing without an `Autosave` resource.
+#[derive(Default)]
+pub struct AutosaveSystem;
+
+impl<'s> System<'s> for AutosaveSystem {
+    type SystemData = (
+        Read<'s, Time>,
+        Option<Write<'s, Autosave>>,
+        Option<Read<'s, SharedTerrain>>,
+        Write<'s, EventChannel<SaveEvent>>,
+    );
+
+    fn run(&mut self, (time, autosave, terrain, mut events): Self::SystemData) {
+        let (mut autosave, terrain) = match (autosave, terrain) {
+            (Some(autosave), Some(terrain)) => (autosave, terrain),
+            _ => return,
+        };
+        match autosave.poll(&terrain) {
+            Some(Ok(())) => events.single_write(SaveEvent::Completed),
+            Some(Err(e)) => events.single_write(SaveEvent::Failed(e.to_string())),
+            None => {}
+        }
+        autosave.timer += time.delta_seconds();
+        if autosave.timer >= autosave.interval {
+            autosave.timer = 0.0;
+            if autosave.start(&terrain) {
+                events.single_write(SaveEvent::Started);
+            }
+        }
+    }
+}
@@ -0,0 +1,142 @@
+use rand::{prelude::StdRng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+/// Seed of the terrain generator, the 32 bytes the per-layer noise seeds are drawn from.
+///
+/// Built from a `u128` by hashing it into all 32 bytes, so seeds that differ in a single
+/// bit still give unrelated terrain, or from raw bytes with `Seed::new`. Deserializes
+/// from an unsigned integer or the 32 raw bytes, and serializes as the raw bytes so a
+/// saved seed loads back unchanged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(from = "SeedRepr", into = "[u8; 32]")]
+pub struct Seed([u8; 32]);
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SeedRepr {
+    Number(u64),
+    Bytes([u8; 32]),
+}
+
+impl From<SeedRepr> for Seed {
+    fn from(repr: SeedRepr) -> Self {
+        match repr {
+            SeedRepr::Number(seed) => Seed::from(seed),
+            SeedRepr::Bytes(bytes) => Seed(bytes),
+        }
+    }
+}
+
+impl From<Seed> for [u8; 32] {
+    fn from(seed: Seed) -> Self {
+        seed.0
+    }
+}
+
+impl Seed {
+    pub fn new(bytes: [u8; 32]) -> Self {
+        Seed(bytes)
+    }
+
+    pub fn bytes(&self) -> [u8; 32] {
+        self.0
+    }
+
+    /// Random number generator drawing the noise seeds, the same for the same seed.
+    pub fn rng(&self) -> StdRng {
+        StdRng::from_seed(self.0)
+    }
+}
+
+/// 128-bit FNV-1a hash of `text`'s UTF-8 bytes, turning a world name into a seed. FNV-1a
+/// is fully specified, so a name gives the same seed on every platform and release.
+pub fn seed_from_str(text: &str) -> u128 {
+    const FNV_OFFSET_BASIS: u128 = 0x6c62_272e_07bb_0142_62b8_2175_6295_c58d;
+    const FNV_PRIME: u128 = 0x0000_0000_0100_0000_0000_0000_0000_013b;
+    let mut hash = FNV_OFFSET_BASIS;
+    for &byte in text.as_bytes() {
+        hash ^= u128::from(byte);
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash
+}
+
+/// SplitMix64 finalizer, a fixed bijective mix of 64 bits. Unlike std's hashers its
+/// output is guaranteed not to change between Rust releases.
+fn mix64(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+impl From<u128> for Seed {
+    fn from(seed: u128) -> Self {
+        const GOLDEN_GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
+        let (high, low) = ((seed >> 64) as u64, seed as u64);
+        let mut bytes = [0; 32];
+        for (i, word) in bytes.chunks_exact_mut(8).enumerate() {
+            let key = GOLDEN_GAMMA.wrapping_mul(i as u64 + 1);
+            let mixed = mix64(mix64(high.wrapping_add(key)) ^ low);
+            word.copy_from_slice(&mixed.to_le_bytes());
+        }
+        Seed(bytes)
+    }
+}
+
+impl From<u64> for Seed {
+    fn from(seed: u64) -> Self {
+        Seed::from(u128::from(seed))
+    }
+}
+
+impl From<&str> for Seed {
+    fn from(text: &str) -> Self {
+        Seed::from(seed_from_str(text))
+    }
+}
+
+impl From<[u8; 32]> for Seed {
+    fn from(bytes: [u8; 32]) -> Self {
+        Seed(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+
+    // Changing any of these changes every existing world
+    #[test]
+    fn u128_seed_expansion_is_pinned() {
+        let bytes = [
+            0xec, 0xb8, 0x9a, 0x0b, 0x15, 0x88, 0x21, 0x5e, 0x8d, 0xde, 0x4c, 0x6c, 0x9c, 0xe0,
+            0x1c, 0x3d, 0x86, 0x45, 0xee, 0x85, 0x57, 0x16, 0xe1, 0x95, 0x6e, 0x63, 0xd5, 0x6a,
+            0x16, 0x48, 0x17, 0x79,
+        ];
+        assert_eq!(Seed::from(277u128).bytes(), bytes);
+        assert_eq!(Seed::from(277u64), Seed::from(277u128));
+    }
+
+    #[test]
+    fn layer_noise_seeds_are_pinned() {
+        // The first draws seed the noise layers, in order
+        let mut rng = Seed::from(277u128).rng();
+        let draws: Vec<u32> = (0..4).map(|_| rng.gen()).collect();
+        assert_eq!(draws, [0x39de_7f72, 0x0a31_f7ef, 0xe75a_2226, 0x266e_3fe1]);
+    }
+
+    #[test]
+    fn string_seeds_are_pinned() {
+        // The empty string hashes to the FNV-1a offset basis
+        assert_eq!(seed_from_str(""), 0x6c62_272e_07bb_0142_62b8_2175_6295_c58d);
+        assert_eq!(seed_from_str("kyro"), 0x6946_9795_9e75_7277_b806_e96a_14b9_e5e2);
+        assert_eq!(seed_from_str("my world"), 0x53da_1375_2665_9b11_0089_467b_ccac_7047);
+        let noise_seeds = |text: &str| {
+            let mut rng = Seed::from(text).rng();
+            (0..3).map(|_| rng.gen()).collect::<Vec<u32>>()
+        };
+        assert_eq!(noise_seeds("kyro"), [0xeb39_c9eb, 0x3f30_1a00, 0x8920_66fe]);
+        assert_eq!(noise_seeds("my world"), [0x62f9_efb5, 0x0fb8_1e9e, 0xfe01_87d7]);
+    }
+}
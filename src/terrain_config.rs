@@ -0,0 +1,224 @@
+use serde::{Deserialize, Serialize};
+use std::{cmp::Ordering, error::Error, fmt, io};
+
+use crate::{
+    seed::Seed,
+    terrain::{
+        TerrainError, DEFAULT_LOWER_BOUND, DEFAULT_NOISE_SCALES, DEFAULT_NOISE_WEIGHTS,
+        DEFAULT_UPPER_BOUND,
+    },
+};
+
+/// Everything needed to generate a terrain, loadable from a RON file so the world shape
+/// can be tuned without recompiling. Fields left out of the file keep their defaults:
+///
+/// ```ron
+/// (
+///     seed: 42,
+///     points_per_chunk: 15,
+///     scale: 1.0,
+///     noise_weights: [0.3, 0.65, 0.05],
+///     noise_scales: [0.05, 0.1, 10.0],
+///     upper_bound: [(-140.0, -1.0), (0.0, 0.35), (50.0, 1.0)],
+///     lower_bound: [(-140.0, -1.0), (0.0, -0.65), (50.0, 1.0)],
+///     sea_level: Some(-5.0),
+/// )
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TerrainConfig {
+    pub seed: Seed,
+    pub points_per_chunk: u16,
+    /// Grid steps along the height of a chunk, `points_per_chunk` if left out.
+    pub vertical_points: Option<u16>,
+    pub scale: f32,
+    pub noise_weights: Vec<f32>,
+    pub noise_scales: Vec<f32>,
+    /// `(height, density)` keys of the upper density bound, in increasing height.
+    pub upper_bound: Vec<(f32, f32)>,
+    /// `(height, density)` keys of the lower density bound, in increasing height.
+    pub lower_bound: Vec<(f32, f32)>,
+    /// Height below which open space is filled with water, none if left out.
+    pub sea_level: Option<f32>,
+    /// Height below which the terrain is always solid, none if left out.
+    pub bedrock_y: Option<f32>,
+    /// Height above which the terrain is always air, none if left out.
+    pub sky_limit_y: Option<f32>,
+    /// Grid points chunk densities are blurred over before meshing, 0 if left out.
+    pub smoothing: usize,
+}
+
+impl TerrainConfig {
+    pub fn new(seed: impl Into<Seed>) -> Self {
+        TerrainConfig {
+            seed: seed.into(),
+            points_per_chunk: 15,
+            vertical_points: None,
+            scale: 1.0,
+            noise_weights: DEFAULT_NOISE_WEIGHTS.to_vec(),
+            noise_scales: DEFAULT_NOISE_SCALES.to_vec(),
+            upper_bound: DEFAULT_UPPER_BOUND.to_vec(),
+            lower_bound: DEFAULT_LOWER_BOUND.to_vec(),
+            sea_level: None,
+            bedrock_y: None,
+            sky_limit_y: None,
+            smoothing: 0,
+        }
+    }
+
+    /// Checks the parts of the config the terrain builder can't, reporting the field at
+    /// fault.
+    pub fn validate(&self) -> Result<(), TerrainConfigError> {
+        if self.noise_weights.len() != self.noise_scales.len() {
+            return Err(TerrainConfigError::LayerMismatch {
+                weights: self.noise_weights.len(),
+                scales: self.noise_scales.len(),
+            });
+        }
+        validate_keys("upper_bound", &self.upper_bound)?;
+        validate_keys("lower_bound", &self.lower_bound)?;
+        Ok(())
+    }
+}
+
+impl Default for TerrainConfig {
+    fn default() -> Self {
+        TerrainConfig::new(0u64)
+    }
+}
+
+fn validate_keys(field: &'static str, keys: &[(f32, f32)]) -> Result<(), TerrainConfigError> {
+    if keys.len() < 2 {
+        return Err(TerrainConfigError::TooFewKeys {
+            field,
+            keys: keys.len(),
+        });
+    }
+    for (index, pair) in keys.windows(2).enumerate() {
+        if pair[1].0.partial_cmp(&pair[0].0) != Some(Ordering::Greater) {
+            return Err(TerrainConfigError::UnorderedKeys {
+                field,
+                index: index + 1,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Reasons a `TerrainConfig` can't be loaded or turned into a terrain.
+#[derive(Debug)]
+pub enum TerrainConfigError {
+    Io(io::Error),
+    Parse(ron::Error),
+    /// `noise_weights` and `noise_scales` have different lengths.
+    LayerMismatch { weights: usize, scales: usize },
+    /// A spline field has fewer than two keys.
+    TooFewKeys { field: &'static str, keys: usize },
+    /// The key at `index` of a spline field isn't higher than the one before it.
+    UnorderedKeys { field: &'static str, index: usize },
+    Terrain(TerrainError),
+}
+
+impl fmt::Display for TerrainConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TerrainConfigError::Io(e) => write!(f, "couldn't read terrain config: {}", e),
+            TerrainConfigError::Parse(e) => write!(f, "couldn't parse terrain config: {}", e),
+            TerrainConfigError::LayerMismatch { weights, scales } => write!(
+                f,
+                "noise_weights has {} entries but noise_scales has {}",
+                weights, scales
+            ),
+            TerrainConfigError::TooFewKeys { field, keys } => {
+                write!(f, "{} needs at least 2 keys, found {}", field, keys)
+            }
+            TerrainConfigError::UnorderedKeys { field, index } => write!(
+                f,
+                "{} key {} isn't higher than the key before it",
+                field, index
+            ),
+            TerrainConfigError::Terrain(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for TerrainConfigError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            TerrainConfigError::Io(e) => Some(e),
+            TerrainConfigError::Parse(e) => Some(e),
+            TerrainConfigError::Terrain(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for TerrainConfigError {
+    fn from(e: io::Error) -> Self {
+        TerrainConfigError::Io(e)
+    }
+}
+
+impl From<ron::Error> for TerrainConfigError {
+    fn from(e: ron::Error) -> Self {
+        TerrainConfigError::Parse(e)
+    }
+}
+
+impl From<TerrainError> for TerrainConfigError {
+    fn from(e: TerrainError) -> Self {
+        TerrainConfigError::Terrain(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mismatched_layers_are_rejected() {
+        let config = TerrainConfig {
+            noise_weights: vec![0.5, 0.5],
+            ..TerrainConfig::new(1u64)
+        };
+        match config.validate() {
+            Err(TerrainConfigError::LayerMismatch { weights, scales }) => {
+                assert_eq!((weights, scales), (2, DEFAULT_NOISE_SCALES.len()));
+            }
+            other => panic!("expected a layer mismatch, got {:?}", other),
+        }
+        // The error has no single field, so its message names both
+        let message = config.validate().unwrap_err().to_string();
+        assert!(message.contains("noise_weights") && message.contains("noise_scales"));
+    }
+
+    #[test]
+    fn splines_with_too_few_keys_are_rejected() {
+        let config = TerrainConfig {
+            lower_bound: vec![(0.0, -1.0)],
+            ..TerrainConfig::new(1u64)
+        };
+        match config.validate() {
+            Err(TerrainConfigError::TooFewKeys { field, keys }) => {
+                assert_eq!(field, "lower_bound");
+                assert_eq!(keys, 1);
+            }
+            other => panic!("expected too few keys, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn unordered_spline_keys_are_rejected() {
+        let config = TerrainConfig {
+            upper_bound: vec![(-10.0, -1.0), (10.0, 0.0), (10.0, 0.5), (50.0, 1.0)],
+            ..TerrainConfig::new(1u64)
+        };
+        match config.validate() {
+            Err(TerrainConfigError::UnorderedKeys { field, index }) => {
+                assert_eq!(field, "upper_bound");
+                assert_eq!(index, 2);
+            }
+            other => panic!("expected unordered keys, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,321 @@
+use amethyst::core::math::Vector3;
+use serde::{Deserialize, Serialize};
+use std::{
+    error::Error,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    matrix_3d::Matrix3D,
+    terrain::Terrain,
+    terrain_config::{TerrainConfig, TerrainConfigError},
+};
+
+/// Version of the save format written by `WorldSave::save`. Saves with any other version
+/// are rejected rather than misread.
+pub const SAVE_VERSION: u32 = 1;
+
+const WORLD_FILE: &str = "world.ron";
+const EDITS_FILE: &str = "edits.bin";
+
+/// The human readable part of a save: the format version and the terrain config.
+#[derive(Serialize, Deserialize)]
+struct WorldMeta {
+    version: u32,
+    config: TerrainConfig,
+}
+
+/// Density deltas of one edited chunk.
+#[derive(Serialize, Deserialize)]
+struct ChunkEdit {
+    chunk: [i32; 3],
+    size: [usize; 3],
+    deltas: Vec<f32>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct EditsFile {
+    version: u32,
+    chunks: Vec<ChunkEdit>,
+}
+
+/// A copy of everything `WorldSave` stores, independent of the terrain it was taken from.
+pub struct WorldSnapshot {
+    config: TerrainConfig,
+    edits: EditsFile,
+}
+
+impl WorldSnapshot {
+    /// Fails for terrain that can't be described by a `TerrainConfig`, since loading it
+    /// would rebuild different terrain under the saved edits.
+    pub fn capture(terrain: &Terrain) -> Result<Self, WorldSaveError> {
+        let config = terrain.config().ok_or(WorldSaveError::NoConfig)?;
+        let chunks = terrain
+            .edited_chunks()
+            .map(|(chunk, edit)| ChunkEdit {
+                chunk: [chunk.x, chunk.y, chunk.z],
+                size: [edit.x(), edit.y(), edit.z()],
+                deltas: edit.as_slice().to_vec(),
+            })
+            .collect();
+        Ok(WorldSnapshot {
+            config,
+            edits: EditsFile {
+                version: SAVE_VERSION,
+                chunks,
+            },
+        })
+    }
+}
+
+/// A world directory on disk, holding the terrain config in `world.ron` and the density
+/// edits of every edited chunk in `edits.bin`. Unedited chunks regenerate from the seed,
+/// so they aren't stored.
+#[derive(Debug, Clone)]
+pub struct WorldSave {
+    dir: PathBuf,
+}
+
+impl WorldSave {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        WorldSave { dir: dir.into() }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Whether the directory holds a save.
+    pub fn exists(&self) -> bool {
+        self.dir.join(WORLD_FILE).is_file()
+    }
+
+    /// Writes the config of `terrain`, see `Terrain::config`, and its edits. Each file is
+    /// written next to its final name and then renamed over it, so a crash mid-save leaves
+    /// the previous file intact.
+    pub fn save(&self, terrain: &Terrain) -> Result<(), WorldSaveError> {
+        self.write(&WorldSnapshot::capture(terrain)?)
+    }
+
+    /// Writes a snapshot taken earlier, so the saving can happen on another thread while
+    /// the terrain keeps changing.
+    pub fn write(&self, snapshot: &WorldSnapshot) -> Result<(), WorldSaveError> {
+        self.save_edits(&snapshot.edits)?;
+        self.save_config(&snapshot.config)
+    }
+
+    /// Writes only the terrain config, leaving the stored edits as they are.
+    pub fn save_config(&self, config: &TerrainConfig) -> Result<(), WorldSaveError> {
+        fs::create_dir_all(&self.dir)?;
+        let meta = WorldMeta {
+            version: SAVE_VERSION,
+            config: config.clone(),
+        };
+        let source = ron::ser::to_string_pretty(&meta, ron::ser::PrettyConfig::default())?;
+        write_atomic(&self.dir.join(WORLD_FILE), source.as_bytes())
+    }
+
+    fn save_edits(&self, edits: &EditsFile) -> Result<(), WorldSaveError> {
+        fs::create_dir_all(&self.dir)?;
+        let bytes = bincode::serialize(edits)?;
+        write_atomic(&self.dir.join(EDITS_FILE), &bytes)
+    }
+
+    /// Rebuilds the saved terrain with its edits.
+    pub fn load(&self) -> Result<Terrain, WorldSaveError> {
+        let source = fs::read_to_string(self.dir.join(WORLD_FILE))?;
+        let meta: WorldMeta = ron::from_str(&source)?;
+        check_version(meta.version)?;
+        let mut terrain = Terrain::from_config(meta.config)?;
+
+        let edits_path = self.dir.join(EDITS_FILE);
+        if edits_path.is_file() {
+            let edits: EditsFile = bincode::deserialize(&fs::read(edits_path)?)?;
+            check_version(edits.version)?;
+            for edit in edits.chunks {
+                let chunk = Vector3::from(edit.chunk);
+                let [x, y, z] = edit.size;
+                let restored = match Matrix3D::from_vec(x, y, z, edit.deltas) {
+                    Some(matrix) => terrain.restore_edit(chunk, matrix),
+                    None => false,
+                };
+                if !restored {
+                    return Err(WorldSaveError::BadEdit { chunk });
+                }
+            }
+        }
+        Ok(terrain)
+    }
+}
+
+fn check_version(version: u32) -> Result<(), WorldSaveError> {
+    if version != SAVE_VERSION {
+        return Err(WorldSaveError::UnsupportedVersion {
+            found: version,
+            supported: SAVE_VERSION,
+        });
+    }
+    Ok(())
+}
+
+/// Writes `bytes` to a temporary file beside `path`, then renames it over `path`.
+fn write_atomic(path: &Path, bytes: &[u8]) -> Result<(), WorldSaveError> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, bytes)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Reasons a world can't be saved or loaded.
+#[derive(Debug)]
+pub enum WorldSaveError {
+    Io(io::Error),
+    Ron(ron::Error),
+    Bincode(bincode::Error),
+    /// The save was written by another version of the format.
+    UnsupportedVersion { found: u32, supported: u32 },
+    /// The stored edits of a chunk don't fit the terrain's chunk size.
+    BadEdit { chunk: Vector3<i32> },
+    /// The terrain was built with settings a `TerrainConfig` can't describe.
+    NoConfig,
+    Config(TerrainConfigError),
+}
+
+impl fmt::Display for WorldSaveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WorldSaveError::Io(e) => write!(f, "couldn't access world save: {}", e),
+            WorldSaveError::Ron(e) => write!(f, "couldn't read or write world config: {}", e),
+            WorldSaveError::Bincode(e) => write!(f, "couldn't read or write world edits: {}", e),
+            WorldSaveError::UnsupportedVersion { found, supported } => write!(
+                f,
+                "world save has format version {}, only version {} is supported",
+                found, supported
+            ),
+            WorldSaveError::BadEdit { chunk } => write!(
+                f,
+                "saved edits of chunk ({}, {}, {}) don't match the chunk size",
+                chunk.x, chunk.y, chunk.z
+            ),
+            WorldSaveError::NoConfig => write!(
+                f,
+                "terrain built with settings a terrain config can't describe can't be saved"
+            ),
+            WorldSaveError::Config(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for WorldSaveError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            WorldSaveError::Io(e) => Some(e),
+            WorldSaveError::Ron(e) => Some(e),
+            WorldSaveError::Bincode(e) => Some(e),
+            WorldSaveError::Config(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for WorldSaveError {
+    fn from(e: io::Error) -> Self {
+        WorldSaveError::Io(e)
+    }
+}
+
+impl From<ron::Error> for WorldSaveError {
+    fn from(e: ron::Error) -> Self {
+        WorldSaveError::Ron(e)
+    }
+}
+
+impl From<bincode::Error> for WorldSaveError {
+    fn from(e: bincode::Error) -> Self {
+        WorldSaveError::Bincode(e)
+    }
+}
+
+impl From<TerrainConfigError> for WorldSaveError {
+    fn from(e: TerrainConfigError) -> Self {
+        WorldSaveError::Config(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::terrain::TerrainBuilder;
+
+    /// A fresh save directory per test, so tests running at once don't share files.
+    fn temp_save(name: &str) -> WorldSave {
+        let dir = std::env::temp_dir().join(format!("kyro-world-save-{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        WorldSave::new(dir)
+    }
+
+    fn small_terrain() -> Terrain {
+        let config = TerrainConfig {
+            points_per_chunk: 8,
+            ..TerrainConfig::new(5u64)
+        };
+        Terrain::from_config(config).unwrap()
+    }
+
+    #[test]
+    fn saved_worlds_load_with_their_edits_and_settings() {
+        let save = temp_save("round-trip");
+        let mut terrain = small_terrain();
+        terrain.set_world_bounds(Some(-20.0), Some(40.0));
+        terrain.set_smoothing(1);
+        terrain.set_sea_level(Some(-2.0));
+        let center = Vector3::new(3.0, 0.0, 3.0);
+        assert!(!terrain.edit_density(center, 3.0, 1.0).is_empty());
+        save.save(&terrain).unwrap();
+
+        let loaded = save.load().unwrap();
+        let settings = |terrain: &Terrain| ron::to_string(&terrain.config().unwrap()).unwrap();
+        assert_eq!(settings(&loaded), settings(&terrain));
+        assert_eq!(loaded.edited_chunks().count(), terrain.edited_chunks().count());
+        for i in 0..=20 {
+            let pos = center + Vector3::new(i as f32 * 0.3 - 3.0, 0.5, 0.25);
+            assert_eq!(loaded.density_at(pos), terrain.density_at(pos));
+        }
+        let _ = fs::remove_dir_all(save.dir());
+    }
+
+    #[test]
+    fn saves_of_another_version_are_rejected() {
+        let save = temp_save("version");
+        save.save(&small_terrain()).unwrap();
+        let path = save.dir().join(WORLD_FILE);
+        let source = fs::read_to_string(&path).unwrap();
+        let current = format!("version: {}", SAVE_VERSION);
+        let newer = source.replacen(&current, &format!("version: {}", SAVE_VERSION + 1), 1);
+        assert_ne!(newer, source);
+        fs::write(&path, newer).unwrap();
+        match save.load() {
+            Err(WorldSaveError::UnsupportedVersion { found, supported }) => {
+                assert_eq!((found, supported), (SAVE_VERSION + 1, SAVE_VERSION));
+            }
+            _ => panic!("a save of another version was loaded"),
+        }
+        let _ = fs::remove_dir_all(save.dir());
+    }
+
+    #[test]
+    fn terrain_a_config_cant_describe_isnt_saved() {
+        let save = temp_save("no-config");
+        let terrain = TerrainBuilder::new(5u64)
+            .with_points_per_chunk(8)
+            .with_domain_warp(8.0, 0.05)
+            .build()
+            .unwrap();
+        match save.save(&terrain) {
+            Err(WorldSaveError::NoConfig) => {}
+            _ => panic!("terrain without a config was saved"),
+        }
+        assert!(!save.exists());
+    }
+}